@@ -69,6 +69,10 @@ impl From<UnescapeError> for ServerAddressError {
 
 
 /// Iterator over key value pairs of the form "key=val,key=val"
+///
+/// Empty segments (leading, trailing or doubled commas) are skipped rather than treated as errors.
+/// The spec doesn't forbid them and the reference implementation is similarly lenient, so an
+/// address like "path=/x," parses the same as "path=/x".
 struct AddrKeyVals<'a> {
     str: Split<'a, char>,
 }
@@ -78,6 +82,7 @@ impl<'a> AddrKeyVals<'a> {
         AddrKeyVals { str: s.split(',') }
     }
 
+    /// Returns the next non-empty segment, or None once the input is exhausted
     fn get_next(&mut self) -> Option<&'a str> {
         loop {
             let kvs = self.str.next();
@@ -118,6 +123,7 @@ impl<'a> Iterator for AddrKeyVals<'a> {
 #[derive(Debug)]
 pub struct UnixAddress {
     path: PathBuf,
+    guid: Option<String>,
 }
 
 impl<'a> UnixAddress {
@@ -125,6 +131,20 @@ impl<'a> UnixAddress {
     pub fn path(&'a self) -> &'a Path {
         self.path.as_path()
     }
+
+    /// Returns the GUID of the server, if the address specified one
+    pub fn guid(&'a self) -> Option<&'a str> {
+        self.guid.as_ref().map(|x| &x[..])
+    }
+}
+
+/// Stores the value of a guid option, rejecting duplicates
+fn set_guid(guid: &mut Option<String>, val: String) -> Result<(), ServerAddressError> {
+    if guid.is_some() {
+        return Err((Error::ConflictingOptions, "Duplicate guid specified".to_owned()));
+    }
+    *guid = Some(val);
+    Ok(())
 }
 
 impl FromStr for UnixAddress {
@@ -134,6 +154,7 @@ impl FromStr for UnixAddress {
     fn from_str(opts: &str) -> Result<Self, ServerAddressError> {
         let keyvals = AddrKeyVals::new(opts);
         let mut path = None;
+        let mut guid = None;
         let mut abs = false;
         for kv in keyvals {
             let kv = try!(kv);
@@ -147,7 +168,7 @@ impl FromStr for UnixAddress {
                                     "Duplicate path/abstract specified".to_owned()));
                     }
                 },
-                "guid" => {}, // Ignore for now
+                "guid" => try!(set_guid(&mut guid, kv.1.clone())),
                 _ => return Err((Error::UnknownOption, kv.0))
             }
             if kv.0 == "abstract" {
@@ -161,23 +182,52 @@ impl FromStr for UnixAddress {
             if abs {
                 path = "\0".to_owned() + &path;
             }
-            Ok(UnixAddress { path: PathBuf::from(path) })
+            Ok(UnixAddress { path: PathBuf::from(path), guid: guid })
         }
     }
 }
 
+/// The address family requested by a Tcp address's family option
+#[derive(Debug, PartialEq)]
+enum Family {
+    Ipv4,
+    Ipv6,
+}
+
 /// A DBus Tcp address
 #[derive(Debug)]
 pub struct TcpAddress {
     host: String,
     port: String,
+    family: Option<Family>,
+    guid: Option<String>,
+}
+
+impl<'a> TcpAddress {
+    /// Returns the GUID of the server, if the address specified one
+    pub fn guid(&'a self) -> Option<&'a str> {
+        self.guid.as_ref().map(|x| &x[..])
+    }
 }
 
 impl ToSocketAddrs for TcpAddress {
     type Iter = vec::IntoIter<SocketAddr>;
     /// Returns the Tcp path
     fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
-        (self.host.clone() + ":" + &self.port).to_socket_addrs()
+        // IPv6 literals need brackets to be told apart from the port
+        let addr = if self.host.contains(':') && !self.host.starts_with('[') {
+            "[".to_owned() + &self.host + "]:" + &self.port
+        } else {
+            self.host.clone() + ":" + &self.port
+        };
+        let addrs = try!(addr.to_socket_addrs()).filter(|x| {
+            match self.family {
+                Some(Family::Ipv4) => x.is_ipv4(),
+                Some(Family::Ipv6) => x.is_ipv6(),
+                None => true,
+            }
+        });
+        Ok(addrs.collect::<Vec<_>>().into_iter())
     }
 }
 
@@ -186,8 +236,18 @@ impl FromStr for TcpAddress {
 
     /// Constructs a TcpAddress from a key=value option string
     fn from_str(opts: &str) -> Result<Self, ServerAddressError> {
+        TcpAddress::from_opts(opts, None)
+    }
+}
+
+impl TcpAddress {
+    /// Parses the options shared by tcp and nonce-tcp addresses.  The noncefile option is only
+    /// accepted if noncefile is given somewhere to store it.
+    fn from_opts(opts: &str, mut noncefile: Option<&mut Option<String>>) -> Result<Self, ServerAddressError> {
         let mut host = None;
         let mut port = None;
+        let mut family = None;
+        let mut guid = None;
         for kv in AddrKeyVals::new(opts) {
             let kv = try!(kv);
 
@@ -208,7 +268,26 @@ impl FromStr for TcpAddress {
                                     "Duplicate port specified".to_owned()));
                     }
                 },
-                "guid" => {}, // Ignore for now
+                "family" => {
+                    if family.is_some() {
+                        return Err((Error::ConflictingOptions,
+                                    "Duplicate family specified".to_owned()));
+                    }
+                    family = Some(match kv.1.as_ref() {
+                        "ipv4" => Family::Ipv4,
+                        "ipv6" => Family::Ipv6,
+                        _ => return Err((Error::MalformedKeyValue, kv.1)),
+                    });
+                },
+                "noncefile" if noncefile.is_some() => {
+                    let noncefile = noncefile.as_mut().unwrap();
+                    if noncefile.is_some() {
+                        return Err((Error::ConflictingOptions,
+                                    "Duplicate noncefile specified".to_owned()));
+                    }
+                    **noncefile = Some(kv.1);
+                },
+                "guid" => try!(set_guid(&mut guid, kv.1)),
                 _ => return Err((Error::UnknownOption, kv.0))
             }
         }
@@ -217,7 +296,48 @@ impl FromStr for TcpAddress {
         } else if port == None {
             Err((Error::MissingOption, "No port for tcp socket".to_owned()))
         } else {
-            Ok(TcpAddress { host: host.unwrap(), port: port.unwrap() })
+            Ok(TcpAddress { host: host.unwrap(), port: port.unwrap(), family: family, guid: guid })
+        }
+    }
+}
+
+/// A DBus nonce-tcp address.  The server only accepts connections that start by sending the
+/// contents of the nonce file.
+#[derive(Debug)]
+pub struct NonceTcpAddress {
+    tcp: TcpAddress,
+    noncefile: PathBuf,
+}
+
+impl<'a> NonceTcpAddress {
+    /// Returns the path of the file holding the nonce
+    pub fn noncefile(&'a self) -> &'a Path {
+        self.noncefile.as_path()
+    }
+
+    /// Returns the GUID of the server, if the address specified one
+    pub fn guid(&'a self) -> Option<&'a str> {
+        self.tcp.guid()
+    }
+}
+
+impl ToSocketAddrs for NonceTcpAddress {
+    type Iter = vec::IntoIter<SocketAddr>;
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        self.tcp.to_socket_addrs()
+    }
+}
+
+impl FromStr for NonceTcpAddress {
+    type Err = ServerAddressError;
+
+    /// Constructs a NonceTcpAddress from a key=value option string
+    fn from_str(opts: &str) -> Result<Self, ServerAddressError> {
+        let mut noncefile = None;
+        let tcp = try!(TcpAddress::from_opts(opts, Some(&mut noncefile)));
+        match noncefile {
+            Some(x) => Ok(NonceTcpAddress { tcp: tcp, noncefile: PathBuf::from(x) }),
+            None => Err((Error::MissingOption, "No noncefile for nonce-tcp socket".to_owned())),
         }
     }
 }
@@ -226,13 +346,26 @@ impl FromStr for TcpAddress {
 pub enum ServerAddress {
     Unix(UnixAddress),
     Tcp(TcpAddress),
+    NonceTcp(NonceTcpAddress),
+}
+
+impl<'a> ServerAddress {
+    /// Returns the GUID of the server, if the address specified one
+    pub fn guid(&'a self) -> Option<&'a str> {
+        match *self {
+            ServerAddress::Unix(ref x) => x.guid(),
+            ServerAddress::Tcp(ref x) => x.guid(),
+            ServerAddress::NonceTcp(ref x) => x.guid(),
+        }
+    }
 }
 
 impl FromStr for ServerAddress {
     type Err = ServerAddressError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut sp = s.split(':');
+        // Only the first colon separates the transport; IPv6 hosts contain more of them
+        let mut sp = s.splitn(2, ':');
         if sp.clone().count() != 2 {
             return Err((Error::BadTransportSeparator, s.to_owned()));
         }
@@ -243,6 +376,7 @@ impl FromStr for ServerAddress {
         match transport {
             "unix" => Ok(ServerAddress::Unix(try!(UnixAddress::from_str(opts)))),
             "tcp" => Ok(ServerAddress::Tcp(try!(TcpAddress::from_str(opts)))),
+            "nonce-tcp" => Ok(ServerAddress::NonceTcp(try!(NonceTcpAddress::from_str(opts)))),
             _ => Err((Error::UnknownTransport, transport.to_owned())),
         }
     }
@@ -277,6 +411,33 @@ fn test_key_vals() {
     assert_eq!(a.next().unwrap().unwrap_err().0, Error::MalformedKeyValue);
 }
 
+#[test]
+fn test_key_vals_empty_segments() {
+    assert_eq!(AddrKeyVals::new("").next(), None);
+    assert_eq!(AddrKeyVals::new(",,,").next(), None);
+
+    let mut a = AddrKeyVals::new(",path=/x").map(Result::unwrap);
+    assert_eq!(a.next().unwrap(), ("path".to_string(), "/x".to_string()));
+    assert_eq!(a.next(), None);
+
+    let mut a = AddrKeyVals::new("path=/x,,,").map(Result::unwrap);
+    assert_eq!(a.next().unwrap(), ("path".to_string(), "/x".to_string()));
+    assert_eq!(a.next(), None);
+
+    let mut a = AddrKeyVals::new("foo=bar,,a=b").map(Result::unwrap);
+    assert_eq!(a.next().unwrap(), ("foo".to_string(), "bar".to_string()));
+    assert_eq!(a.next().unwrap(), ("a".to_string(), "b".to_string()));
+    assert_eq!(a.next(), None);
+
+    // A stray comma is ignored, but a segment without '=' is still malformed
+    let mut a = AddrKeyVals::new("path=/x,junk");
+    a.next().unwrap().unwrap();
+    assert_eq!(a.next().unwrap().unwrap_err().0, Error::MalformedKeyValue);
+
+    let addr = UnixAddress::from_str("path=/x,").unwrap();
+    assert_eq!(addr.path(), Path::new("/x"));
+}
+
 #[test]
 fn test_server_address() {
     assert_eq!(ServerAddress::from_str("unix").unwrap_err().0, Error::BadTransportSeparator);
@@ -284,3 +445,51 @@ fn test_server_address() {
     assert_eq!(ServerAddress::from_str("unix:path=/var/run/dbus/system_bus_socket,foo=bar").unwrap_err().0, Error::UnknownOption);
     assert_eq!(ServerAddress::from_str("unix:").unwrap_err().0, Error::MissingOption);
 }
+
+#[test]
+fn test_guid() {
+    let addr = ServerAddress::from_str("unix:path=/tmp/foo,guid=0123456789abcdef0123456789abcdef").unwrap();
+    assert_eq!(addr.guid(), Some("0123456789abcdef0123456789abcdef"));
+    let addr = ServerAddress::from_str("tcp:host=localhost,port=1234,guid=abc").unwrap();
+    assert_eq!(addr.guid(), Some("abc"));
+    let addr = ServerAddress::from_str("unix:path=/tmp/foo").unwrap();
+    assert_eq!(addr.guid(), None);
+    assert_eq!(ServerAddress::from_str("unix:path=/tmp/foo,guid=a,guid=b").unwrap_err().0,
+               Error::ConflictingOptions);
+}
+
+#[test]
+fn test_tcp_address() {
+    let addr = match ServerAddress::from_str("tcp:host=::1,port=1234").unwrap() {
+        ServerAddress::Tcp(x) => x,
+        x => panic!("Expected a tcp address, got {:?}", x)
+    };
+    let addrs: Vec<_> = addr.to_socket_addrs().unwrap().collect();
+    assert_eq!(addrs, vec![SocketAddr::from_str("[::1]:1234").unwrap()]);
+
+    let addr = TcpAddress::from_str("host=%3a%3a1,port=1234,family=ipv6").unwrap();
+    assert_eq!(addr.to_socket_addrs().unwrap().count(), 1);
+    let addr = TcpAddress::from_str("host=::1,port=1234,family=ipv4").unwrap();
+    assert_eq!(addr.to_socket_addrs().unwrap().count(), 0);
+    let addr = TcpAddress::from_str("host=127.0.0.1,port=1234,family=ipv4").unwrap();
+    assert_eq!(addr.to_socket_addrs().unwrap().count(), 1);
+
+    assert_eq!(TcpAddress::from_str("host=::1,port=1,family=ipx").unwrap_err().0,
+               Error::MalformedKeyValue);
+}
+
+#[test]
+fn test_nonce_tcp_address() {
+    let addr = match ServerAddress::from_str("nonce-tcp:host=127.0.0.1,port=12345,noncefile=/tmp/nonce").unwrap() {
+        ServerAddress::NonceTcp(x) => x,
+        x => panic!("Expected a nonce-tcp address, got {:?}", x)
+    };
+    assert_eq!(addr.noncefile(), Path::new("/tmp/nonce"));
+    let addrs: Vec<_> = addr.to_socket_addrs().unwrap().collect();
+    assert_eq!(addrs, vec![SocketAddr::from_str("127.0.0.1:12345").unwrap()]);
+
+    assert_eq!(ServerAddress::from_str("nonce-tcp:host=127.0.0.1,port=12345").unwrap_err().0,
+               Error::MissingOption);
+    assert_eq!(ServerAddress::from_str("tcp:host=127.0.0.1,port=12345,noncefile=/tmp/nonce").unwrap_err().0,
+               Error::UnknownOption);
+}
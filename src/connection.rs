@@ -20,50 +20,221 @@
 //! ```
 //!
 
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap,VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher,Hasher};
 use std::env;
 use std::error;
 use std::fmt;
-use std::net::{TcpStream,ToSocketAddrs};
+use std::net::{TcpStream,ToSocketAddrs,SocketAddr};
 use std::io;
 use std::io::{Read,Write};
 use std::fs::File;
-use std::ops::Deref;
+use std::mem;
+use std::ops::{BitOr,Deref};
 use std::path::Path;
+use std::path::PathBuf;
 use std::cell::RefCell;
 use std::str::FromStr;
 use std::string;
 use std::num::ParseIntError;
+use std::os::unix::io::{AsRawFd,FromRawFd,RawFd};
+use std::ptr;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+#[cfg(feature = "cookie-auth")]
 use rand;
+#[cfg(feature = "cookie-auth")]
 use rand::prelude::*;
 use libc;
+#[cfg(feature = "cookie-auth")]
 use crypto::digest::Digest;
+#[cfg(feature = "cookie-auth")]
 use crypto;
 
 use unix_socket::UnixStream;
-use rustc_serialize::hex::{ToHex,FromHex,FromHexError};
-use dbus_serialize::types::Value;
+use rustc_serialize::Decodable;
+use rustc_serialize::hex::{ToHex,FromHexError};
+#[cfg(feature = "cookie-auth")]
+use rustc_serialize::hex::FromHex;
+use dbus_serialize::types::{Value,BasicValue,Variant};
 use dbus_serialize::decoder::DBusDecoder;
 
 use address;
 use address::ServerAddress;
 use message;
-use message::{Message,HeaderField};
-use demarshal::{demarshal,DemarshalError};
-use marshal::Marshal;
+use message::{Message,Endianness};
+use demarshal::{demarshal_with_order,framed_message_len,DemarshalError};
+use marshal::{Marshal,align_len};
+#[cfg(feature = "async")]
+use stream;
 
 trait StreamSocket : Read + Write { }
 impl<T: Read + Write> StreamSocket for T {}
 
 enum Socket {
     Tcp(TcpStream),
-    Uds(UnixStream)
+    Uds(UnixStream),
+    Other(Box<StreamSocket + Send>),
 }
 
 pub struct Connection {
     sock: RefCell<Socket>,
     serial: RefCell<u32>,
     queue: RefCell<VecDeque<Message>>,
+    unique_name: RefCell<Option<String>>,
+    server_guid: RefCell<Option<String>>,
+    auth_mechanism: RefCell<AuthMechanism>,
+    // File descriptors that have been received but not yet claimed by a message
+    received_fds: RefCell<VecDeque<RawFd>>,
+    pending: RefCell<Vec<u8>>,
+    observer: RefCell<Option<Box<MessageObserver + Send>>>,
+    #[cfg(feature = "async")]
+    pub(crate) async_state: RefCell<stream::AsyncState>,
+}
+
+/// Creates Connections with non-default options.  Connection::connect and the other Connection
+/// constructors are equivalent to using a default ConnectionBuilder.
+///
+/// # Examples
+/// ```
+/// use dbus_bytestream::connection::ConnectionBuilder;
+///
+/// let conn = ConnectionBuilder::new()
+///     .skip_hello(true)
+///     .connect_system()
+///     .unwrap();
+/// conn.hello().unwrap();
+/// ```
+#[derive(Debug,Clone,Default)]
+pub struct ConnectionBuilder {
+    skip_hello: bool,
+    skip_guid_check: bool,
+    tcp_nodelay: bool,
+    tcp_keepalive: bool,
+    tcp_bind_addr: Option<SocketAddr>,
+}
+
+/// The result of asking the bus to start a service
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum StartServiceReply {
+    Started,
+    AlreadyRunning,
+}
+
+/// Receives every message a Connection sends or reads off its socket, for logging or capturing
+/// traffic.  Register one with Connection::set_observer.
+pub trait MessageObserver {
+    /// Called with each message just before it's written to the socket, with its serial set
+    fn on_send(&self, msg: &Message);
+    /// Called with each message as it's read from the socket, including ones that get queued
+    fn on_recv(&self, msg: &Message);
+}
+
+/// The SASL mechanism a Connection authenticated with
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AuthMechanism {
+    External,
+    Cookie,
+    Anonymous,
+    /// The Connection didn't authenticate, as with Connection::pair
+    Unauthenticated,
+}
+
+/// The kind of socket a Connection is using
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Transport {
+    Tcp,
+    Unix,
+    /// A stream handed to Connection::from_stream
+    Other,
+}
+
+/// Flags for Connection::request_name, which can be combined with |
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq)]
+pub struct RequestNameFlags(pub u32);
+
+impl RequestNameFlags {
+    /// Let another connection take the name from us if it asks with REPLACE_EXISTING
+    pub const ALLOW_REPLACEMENT : RequestNameFlags = RequestNameFlags(0x1);
+    /// Take the name from its current owner, if the owner allows it
+    pub const REPLACE_EXISTING : RequestNameFlags  = RequestNameFlags(0x2);
+    /// Fail rather than waiting in the queue if the name is already owned
+    pub const DO_NOT_QUEUE : RequestNameFlags      = RequestNameFlags(0x4);
+}
+
+impl BitOr for RequestNameFlags {
+    type Output = RequestNameFlags;
+    fn bitor(self, other: RequestNameFlags) -> RequestNameFlags {
+        RequestNameFlags(self.0 | other.0)
+    }
+}
+
+/// Builds the match rule strings that Connection::add_match takes.  Rules only match signals, and
+/// each key that's set narrows the rule further.
+///
+/// # Examples
+/// ```
+/// use dbus_bytestream::connection::MatchRule;
+///
+/// let rule = MatchRule::new().interface("org.freedesktop.DBus").member("NameOwnerChanged");
+/// assert_eq!(rule.to_string(),
+///            "type='signal',interface='org.freedesktop.DBus',member='NameOwnerChanged'");
+/// ```
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct MatchRule {
+    keys: Vec<(&'static str, String)>,
+}
+
+impl MatchRule {
+    pub fn new() -> MatchRule {
+        Default::default()
+    }
+
+    fn key(mut self, key: &'static str, value: &str) -> MatchRule {
+        self.keys.retain(|x| x.0 != key);
+        self.keys.push((key, value.to_owned()));
+        self
+    }
+
+    /// Only match signals from the given unique or well-known name
+    pub fn sender(self, sender: &str) -> MatchRule {
+        self.key("sender", sender)
+    }
+
+    pub fn interface(self, interface: &str) -> MatchRule {
+        self.key("interface", interface)
+    }
+
+    pub fn member(self, member: &str) -> MatchRule {
+        self.key("member", member)
+    }
+
+    /// Only match signals from the object at path
+    pub fn path(self, path: &str) -> MatchRule {
+        self.key("path", path)
+    }
+}
+
+impl fmt::Display for MatchRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "type='signal'"));
+        for &(key, ref value) in self.keys.iter() {
+            // Quotes can't be escaped inside a quoted value, so they're ended and restarted
+            // around an escaped quote
+            try!(write!(f, ",{}='{}'", key, value.replace("'", "'\\''")));
+        }
+        Ok(())
+    }
+}
+
+/// The result of asking the bus for a well-known name
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RequestNameReply {
+    PrimaryOwner,
+    InQueue,
+    Exists,
+    AlreadyOwner,
 }
 
 #[derive(Debug)]
@@ -74,7 +245,19 @@ pub enum Error {
     AddressError(address::ServerAddressError),
     BadData,
     AuthFailed,
+    AuthRejected,
     NoEnvironment,
+    GuidMismatch,
+    InvalidMessageForCall,
+    Timeout,
+    NotTcp,
+    FdsNotSupported,
+    /// The peer replied to a method call with an error
+    MethodError { name: String, message: Option<String> },
+    /// The object has no property with this name
+    UnknownProperty(String),
+    /// The property with this name can't be set
+    PropertyReadOnly(String),
 }
 
 impl From<io::Error> for Error {
@@ -122,7 +305,17 @@ impl fmt::Display for Error {
             Error::AddressError(ref addrerr) => write!(f, "address error: {:?}", addrerr),
             Error::BadData                   => write!(f, "bad data"),
             Error::AuthFailed                => write!(f, "authentication failed"),
+            Error::AuthRejected              => write!(f, "authentication rejected"),
             Error::NoEnvironment             => write!(f, "no environment"),
+            Error::GuidMismatch              => write!(f, "server GUID doesn't match address"),
+            Error::InvalidMessageForCall     => write!(f, "message can't be used for a call"),
+            Error::Timeout                   => write!(f, "timed out"),
+            Error::NotTcp                    => write!(f, "not a TCP connection"),
+            Error::FdsNotSupported           => write!(f, "file descriptors can't be passed on this connection"),
+            Error::MethodError { ref name, message: Some(ref msg) } => write!(f, "{}: {}", name, msg),
+            Error::MethodError { ref name, message: None } => write!(f, "{}", name),
+            Error::UnknownProperty(ref name) => write!(f, "unknown property: {}", name),
+            Error::PropertyReadOnly(ref name) => write!(f, "property is read-only: {}", name),
         }
     }
 }
@@ -149,11 +342,18 @@ fn read_exactly(sock: &mut StreamSocket, buf: &mut Vec<u8>, len: usize) -> Resul
     Ok(())
 }
 
+/// The longest line we'll accept from the server during authentication, including the CRLF.
+/// libdbus uses the same limit.
+const MAX_AUTH_LINE : usize = 16384;
+
 fn read_line(sock: &mut StreamSocket) -> Result<String,Error> {
     let mut line = "".to_owned();
     let mut last = '\0';
 
     loop {
+        if line.len() >= MAX_AUTH_LINE {
+            return Err(Error::AuthFailed);
+        }
         let mut buf = vec![0];
         match sock.read(&mut buf) {
             Ok(x) if x > 0 => (),
@@ -169,12 +369,283 @@ fn read_line(sock: &mut StreamSocket) -> Result<String,Error> {
     Ok(line)
 }
 
-fn get_cookie(context: &str, cookie_id: &str) -> Result<String,Error> {
-    let hd = match env::home_dir() {
-        Some(x) => x,
-        None => return Err(Error::AuthFailed)
+/// Enables or disables SO_KEEPALIVE on sock
+fn set_keepalive(sock: &TcpStream, keepalive: bool) -> io::Result<()> {
+    let val = keepalive as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(sock.as_raw_fd(), libc::SOL_SOCKET, libc::SO_KEEPALIVE,
+                         &val as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn raw_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage : libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(ref a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Connects to remote from the local address local.  TcpStream::connect has no way to bind the
+/// socket first, so this makes the socket by hand.
+fn tcp_connect_from(local: &SocketAddr, remote: &SocketAddr) -> io::Result<TcpStream> {
+    let family = match *remote {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owning the fd right away makes sure it's closed if anything below fails
+    let sock = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let (addr, len) = raw_sockaddr(local);
+    if unsafe { libc::bind(fd, &addr as *const libc::sockaddr_storage as *const libc::sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (addr, len) = raw_sockaddr(remote);
+    if unsafe { libc::connect(fd, &addr as *const libc::sockaddr_storage as *const libc::sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+/// Reads the 16 byte nonce that a nonce-tcp server expects to be sent first.  Returns AuthFailed
+/// if the file can't be read or doesn't hold a nonce.
+fn read_nonce(path: &Path) -> Result<Vec<u8>,Error> {
+    let mut nonce = Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut nonce)) {
+        Ok(16) => Ok(nonce),
+        _ => Err(Error::AuthFailed)
+    }
+}
+
+/// Sends buf along with fds as SCM_RIGHTS ancillary data.  Returns how much of buf was sent.
+fn send_with_fds(sock: &UnixStream, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+    let fds_len = fds.len() * mem::size_of::<RawFd>();
+    let space = unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize;
+    // u64s keep the control buffer suitably aligned for cmsghdr
+    let mut control = vec![0u64; (space + 7) / 8];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut hdr : libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_iov = &mut iov;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = space as _;
+    let ret = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        libc::sendmsg(sock.as_raw_fd(), &hdr, 0)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Reads into buf, adding any file descriptors that arrive as SCM_RIGHTS ancillary data to fds.
+fn recv_with_fds(sock: &UnixStream, buf: &mut [u8], fds: &mut VecDeque<RawFd>) -> io::Result<usize> {
+    let mut control = [0u64; 128];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
     };
-    let filename = hd.join(".dbus-keyrings").join(context);
+    let mut hdr : libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_iov = &mut iov;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = mem::size_of_val(&control) as _;
+    let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut hdr, libc::MSG_CMSG_CLOEXEC) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut received = VecDeque::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg);
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                let data = data as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    received.push_back(ptr::read_unaligned(data.offset(i as isize)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+    }
+    // Some descriptors didn't fit and were dropped, so the ones that did can't be matched up with
+    // the message they came with
+    if hdr.msg_flags & libc::MSG_CTRUNC != 0 {
+        close_fds(&mut received);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "file descriptors were truncated"));
+    }
+    fds.append(&mut received);
+    Ok(ret as usize)
+}
+
+/// Closes and removes all of the descriptors in fds
+fn close_fds(fds: &mut VecDeque<RawFd>) {
+    for fd in fds.drain(..) {
+        unsafe { libc::close(fd); }
+    }
+}
+
+/// Wraps a UnixStream so that reading from it collects any file descriptors sent along with the
+/// data
+struct FdReader<'a> {
+    sock: &'a mut UnixStream,
+    fds: &'a mut VecDeque<RawFd>,
+}
+
+impl<'a> Read for FdReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        recv_with_fds(self.sock, buf, self.fds)
+    }
+}
+
+impl<'a> Write for FdReader<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+/// Reads any data that try_read_msg has already taken off the socket before reading more from it
+struct PendingReader<'a> {
+    pending: &'a mut Vec<u8>,
+    sock: &'a mut StreamSocket,
+}
+
+impl<'a> Read for PendingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return self.sock.read(buf);
+        }
+        let len = cmp::min(buf.len(), self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl<'a> Write for PendingReader<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+/// Builds an Error::MethodError from an error reply
+pub(crate) fn method_error(msg: &Message) -> Error {
+    let name = match msg.get_header(message::HEADER_FIELD_ERROR_NAME).map(|x| &*x.object) {
+        Some(&Value::BasicValue(BasicValue::String(ref x))) => x.clone(),
+        _ => "".to_owned()
+    };
+    let message = match msg.get_body() {
+        Ok(Some(ref x)) if !x.is_empty() => match x[0] {
+            Value::BasicValue(BasicValue::String(ref x)) => Some(x.clone()),
+            _ => None
+        },
+        _ => None
+    };
+    Error::MethodError { name: name, message: message }
+}
+
+/// Turns the errors that a property access can fail with into the more specific Error variants
+fn property_error(e: Error, name: &str) -> Error {
+    match e {
+        Error::MethodError { name: ref err, .. }
+            if err == "org.freedesktop.DBus.Error.UnknownProperty" =>
+                Error::UnknownProperty(name.to_owned()),
+        Error::MethodError { name: ref err, .. }
+            if err == "org.freedesktop.DBus.Error.PropertyReadOnly" =>
+                Error::PropertyReadOnly(name.to_owned()),
+        e => e
+    }
+}
+
+/// Creates a method call to the bus driver, org.freedesktop.DBus
+fn create_driver_call(method: &str) -> Message {
+    message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                "org.freedesktop.DBus", method)
+}
+
+/// Checks the server's final response to an AUTH exchange, returning the server's GUID on success.
+/// REJECTED is reported separately from other failures, since the server is then ready for us to
+/// try another mechanism.
+fn check_auth_response(resp: &str) -> Result<String,Error> {
+    if resp.starts_with("OK ") {
+        Ok(resp[3..].trim().to_owned())
+    } else if resp.starts_with("REJECTED") {
+        Err(Error::AuthRejected)
+    } else {
+        Err(Error::AuthFailed)
+    }
+}
+
+/// Generates a GUID suitable for a server to send in its "OK <guid>" line.  As in the reference
+/// implementation, this is 12 random bytes followed by the current time in seconds as a 32-bit
+/// big-endian number, all written as 32 hex digits.
+pub fn generate_guid() -> String {
+    let mut guid = vec![0; 12];
+    // Fall back to the keys std seeds its hashers with if /dev/urandom can't be read
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut guid)).is_err() {
+        for chunk in guid.chunks_mut(8) {
+            let bytes = RandomState::new().build_hasher().finish().to_le_bytes();
+            let len = chunk.len();
+            chunk.copy_from_slice(&bytes[..len]);
+        }
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0) as u32;
+    for i in 0..4 {
+        guid.push((now >> (24 - i * 8)) as u8);
+    }
+    guid.to_hex()
+}
+
+#[cfg(feature = "cookie-auth")]
+fn keyring_dir() -> Result<PathBuf,Error> {
+    match env::home_dir() {
+        Some(x) => Ok(x.join(".dbus-keyrings")),
+        None => Err(Error::AuthFailed)
+    }
+}
+
+#[cfg(feature = "cookie-auth")]
+fn get_cookie(keyring: &Path, context: &str, cookie_id: &str) -> Result<String,Error> {
+    let filename = keyring.join(context);
     let mut f = try!(File::open(filename));
     let mut contents = String::new();
     try!(f.read_to_string(&mut contents));
@@ -201,6 +672,47 @@ impl Connection {
         match *sock {
             Socket::Tcp(ref mut x) => f(x),
             Socket::Uds(ref mut x) => f(x),
+            Socket::Other(ref mut x) => f(&mut **x),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(),Error> {
+        let sock = self.sock.borrow();
+
+        try!(match *sock {
+            Socket::Tcp(ref x) => x.set_read_timeout(timeout),
+            Socket::Uds(ref x) => x.set_read_timeout(timeout),
+            Socket::Other(_) => Err(io::Error::new(io::ErrorKind::Other,
+                                                   "stream doesn't support read timeouts")),
+        });
+        Ok(())
+    }
+
+    /// Returns the kind of socket the connection is using.  Only Unix sockets can pass file
+    /// descriptors.
+    pub fn transport(&self) -> Transport {
+        match *self.sock.borrow() {
+            Socket::Tcp(_) => Transport::Tcp,
+            Socket::Uds(_) => Transport::Unix,
+            Socket::Other(_) => Transport::Other,
+        }
+    }
+
+    /// Sets TCP_NODELAY on the underlying socket.  Returns Error::NotTcp if the connection isn't
+    /// using TCP.
+    pub fn set_tcp_nodelay(&self, nodelay: bool) -> Result<(),Error> {
+        match *self.sock.borrow() {
+            Socket::Tcp(ref x) => Ok(try!(x.set_nodelay(nodelay))),
+            _ => Err(Error::NotTcp),
+        }
+    }
+
+    /// Sets SO_KEEPALIVE on the underlying socket.  Returns Error::NotTcp if the connection isn't
+    /// using TCP.
+    pub fn set_keepalive(&self, keepalive: bool) -> Result<(),Error> {
+        match *self.sock.borrow() {
+            Socket::Tcp(ref x) => Ok(try!(set_keepalive(x, keepalive))),
+            _ => Err(Error::NotTcp),
         }
     }
 
@@ -215,25 +727,23 @@ impl Connection {
         self.run_sock(Self::sock_send_nul_byte)
     }
 
-    fn sock_auth_anonymous(sock: &mut StreamSocket) -> Result<(),Error> {
+    fn sock_auth_anonymous(sock: &mut StreamSocket) -> Result<String,Error> {
         try!(sock.write_all(b"AUTH ANONYMOUS 6c69626462757320312e382e3132\r\n"));
 
         // Read response
         let resp = try!(read_line(sock));
-        if !resp.starts_with("OK ") {
-            return Err(Error::AuthFailed);
-        }
+        let guid = try!(check_auth_response(&resp));
 
         // Ready for action
         try!(sock.write_all(b"BEGIN\r\n"));
-        Ok(())
+        Ok(guid)
     }
 
-    fn auth_anonymous(&self) -> Result<(),Error> {
+    fn auth_anonymous(&self) -> Result<String,Error> {
         self.run_sock(Self::sock_auth_anonymous)
     }
 
-    fn sock_auth_external(sock: &mut StreamSocket) -> Result<(),Error> {
+    fn sock_auth_external(sock: &mut StreamSocket) -> Result<String,Error> {
         let uid = unsafe {
             libc::getuid()
         };
@@ -244,20 +754,48 @@ impl Connection {
 
         // Read response
         let resp = try!(read_line(sock));
-        if !resp.starts_with("OK ") {
-            return Err(Error::AuthFailed);
-        }
+        let guid = try!(check_auth_response(&resp));
 
         // Ready for action
         try!(sock.write_all(b"BEGIN\r\n"));
-        Ok(())
+        Ok(guid)
     }
 
-    fn auth_external(&self) -> Result<(),Error> {
+    fn auth_external(&self) -> Result<String,Error> {
         self.run_sock(Self::sock_auth_external)
     }
 
-    fn sock_auth_cookie(sock: &mut StreamSocket) -> Result<(),Error> {
+    #[cfg(feature = "cookie-auth")]
+    fn sock_auth_cookie(sock: &mut StreamSocket) -> Result<String,Error> {
+        let keyring = try!(keyring_dir());
+        Self::sock_auth_cookie_keyring(sock, &keyring)
+    }
+
+    #[cfg(feature = "cookie-auth")]
+    fn sock_auth_cookie_keyring(sock: &mut StreamSocket, keyring: &Path) -> Result<String,Error> {
+        let mut used = None;
+        match Self::sock_auth_cookie_once(sock, keyring, &mut used) {
+            Err(Error::AuthRejected) => (),
+            x => return x
+        };
+        // The server rejected our hash.  If the keyring was rotated underneath us we may have
+        // used a stale cookie, so try once more with a freshly read one.
+        match used {
+            Some((context, cookie_id, cookie)) => {
+                if get_cookie(keyring, &context, &cookie_id).ok() == Some(cookie) {
+                    return Err(Error::AuthRejected);
+                }
+                Self::sock_auth_cookie_once(sock, keyring, &mut None)
+            },
+            None => Err(Error::AuthRejected)
+        }
+    }
+
+    /// Runs a single DBUS_COOKIE_SHA1 exchange.  The context, id and value of the cookie that was
+    /// used are stored in used, so the caller can tell if it changed.
+    #[cfg(feature = "cookie-auth")]
+    fn sock_auth_cookie_once(sock: &mut StreamSocket, keyring: &Path,
+                             used: &mut Option<(String, String, String)>) -> Result<String,Error> {
         let uid = unsafe {
             libc::getuid()
         };
@@ -268,6 +806,9 @@ impl Connection {
 
         // Read response
         let resp = try!(read_line(sock));
+        if resp.starts_with("REJECTED") {
+            return Err(Error::AuthRejected);
+        }
         let words : Vec<&str> = resp.split(' ').collect();
         if words.len() != 2 {
             return Err(Error::AuthFailed);
@@ -283,7 +824,8 @@ impl Connection {
             return Err(Error::AuthFailed);
         }
 
-        let cookie = try!(get_cookie(words[0], words[1]));
+        let cookie = try!(get_cookie(keyring, words[0], words[1]));
+        *used = Some((words[0].to_owned(), words[1].to_owned(), cookie.clone()));
 
         let mut my_challenge = Vec::new();
         let mut rng = rand::thread_rng();
@@ -304,46 +846,95 @@ impl Connection {
 
         // Read response
         let resp = try!(read_line(sock));
-        if !resp.starts_with("OK ") {
-            return Err(Error::AuthFailed);
-        }
+        let guid = try!(check_auth_response(&resp));
 
         // Ready for action
         try!(sock.write_all(b"BEGIN\r\n"));
-        Ok(())
+        Ok(guid)
     }
 
-    fn auth_cookie(&self) -> Result<(),Error> {
+    #[cfg(feature = "cookie-auth")]
+    fn auth_cookie(&self) -> Result<String,Error> {
         self.run_sock(Self::sock_auth_cookie)
     }
 
+    #[cfg(not(feature = "cookie-auth"))]
+    fn auth_cookie(&self) -> Result<String,Error> {
+        Err(Error::AuthFailed)
+    }
+
     fn authenticate(&self) -> Result<(),Error> {
         try!(self.send_nul_byte());
-        try!(self.auth_external()
-              .or_else(|_x| { self.auth_cookie() })
-              .or_else(|_x| { self.auth_anonymous() }));
-        self.say_hello()
-    }
-
-    fn say_hello(&self) -> Result<(),Error> {
-        let msg = message::create_method_call("org.freedesktop.DBus",
-                                              "/org/freedesktop/DBus",
-                                              "org.freedesktop.DBus",
-                                              "Hello");
-        try!(self.call_sync(msg));
+        let (guid, mechanism) = try!(self.auth_external().map(|x| (x, AuthMechanism::External))
+              .or_else(|_x| { self.auth_cookie().map(|x| (x, AuthMechanism::Cookie)) })
+              .or_else(|_x| { self.auth_anonymous().map(|x| (x, AuthMechanism::Anonymous)) }));
+        *self.server_guid.borrow_mut() = Some(guid);
+        *self.auth_mechanism.borrow_mut() = mechanism;
         Ok(())
     }
 
-    fn connect_addr(addr: ServerAddress) -> Result<Connection,Error> {
-        match addr {
-            ServerAddress::Unix(unix) => Self::connect_uds(unix.path()),
-            ServerAddress::Tcp(tcp) => Self::connect_tcp(tcp),
+    /// Returns the GUID the server sent when authentication completed.
+    pub fn server_guid(&self) -> Option<String> {
+        self.server_guid.borrow().clone()
+    }
+
+    /// Returns the mechanism that authentication succeeded with.
+    pub fn auth_mechanism(&self) -> AuthMechanism {
+        *self.auth_mechanism.borrow()
+    }
+
+    /// Sends the Hello message to the bus, which assigns the connection its unique name.  The
+    /// Connection constructors do this automatically, so this is only needed for connections
+    /// created with ConnectionBuilder::skip_hello.
+    pub fn hello(&self) -> Result<(),Error> {
+        let msg = create_driver_call("Hello");
+        let name = match try!(self.call_sync(msg)) {
+            Some(ref x) if x.len() == 1 => match DBusDecoder::decode::<String>(x[0].clone()) {
+                Ok(name) => name,
+                Err(_) => return Err(Error::BadData)
+            },
+            _ => return Err(Error::BadData)
+        };
+        *self.unique_name.borrow_mut() = Some(name);
+        Ok(())
+    }
+
+    /// Returns the unique name assigned to this connection by the bus, or None if Hello hasn't
+    /// been sent yet.
+    pub fn unique_name(&self) -> Option<String> {
+        self.unique_name.borrow().clone()
+    }
+
+    fn new(sock: Socket) -> Connection {
+        Connection {
+            sock: RefCell::new(sock),
+            queue: RefCell::new(VecDeque::new()),
+            serial: RefCell::new(1),
+            unique_name: RefCell::new(None),
+            server_guid: RefCell::new(None),
+            auth_mechanism: RefCell::new(AuthMechanism::Unauthenticated),
+            received_fds: RefCell::new(VecDeque::new()),
+            pending: RefCell::new(Vec::new()),
+            observer: RefCell::new(None),
+            #[cfg(feature = "async")]
+            async_state: RefCell::new(stream::AsyncState::new()),
         }
     }
 
-    /// Connects to a DBus address string.
+    /// Creates two Connections wired directly to each other, with no bus in between.  Messages
+    /// sent on one can be read from the other.  No authentication or Hello takes place, so
+    /// neither end has a unique name.  This is mainly useful for testing code that handles
+    /// messages without needing a running dbus-daemon.
+    pub fn pair() -> Result<(Connection, Connection),Error> {
+        let (a, b) = try!(UnixStream::pair());
+        Ok((Connection::new(Socket::Uds(a)), Connection::new(Socket::Uds(b))))
+    }
+
+    /// Connects to a DBus address string.  The string can hold several addresses separated by
+    /// ';', which are tried in order until one succeeds.  If none do, the error from the last one
+    /// is returned.
     pub fn connect(addr: &str) -> Result<Connection, Error> {
-        Self::connect_addr(try!(ServerAddress::from_str(addr)))
+        ConnectionBuilder::new().connect(addr)
     }
 
     /// Connects to the system bus.
@@ -351,67 +942,93 @@ impl Connection {
     /// The address is specified by the environment variable
     /// DBUS_SYSTEM_BUS_ADDRESS or "unix:path=/var/run/dbus/system_bus_socket" if unset.
     pub fn connect_system() -> Result<Connection, Error> {
-        let default = "unix:path=/var/run/dbus/system_bus_socket";
-        if let Ok(e) = env::var("DBUS_SYSTEM_BUS_ADDRESS") {
-            Self::connect(&e)
-        } else {
-            Self::connect(default)
-        }
+        ConnectionBuilder::new().connect_system()
     }
 
     /// Connects to the session bus.
     ///
-    /// The address is specified by the environment variable DBUS_SESSION_BUS_ADDRESS.
+    /// The address is specified by the environment variable DBUS_SESSION_BUS_ADDRESS.  If that's
+    /// unset, the socket at $XDG_RUNTIME_DIR/bus is used if it exists.
     pub fn connect_session() -> Result<Connection, Error> {
-        if let Ok(e) = env::var("DBUS_SESSION_BUS_ADDRESS") {
-            Self::connect(&e)
-        } else {
-            Err(Error::NoEnvironment)
-        }
+        ConnectionBuilder::new().connect_session()
     }
 
     /// Creates a Connection object using a UNIX domain socket as the transport.  The addr is the
     /// path to connect to.  Abstract paths can be used by passing a NUL byte as the first byte of
     /// addr.
     pub fn connect_uds<P: AsRef<Path>>(addr: P) -> Result<Connection,Error> {
-        let sock = try!(UnixStream::connect(addr));
-        let conn = Connection {
-            sock: RefCell::new(Socket::Uds(sock)),
-            queue: RefCell::new(VecDeque::new()),
-            serial: RefCell::new(1)
-        };
-
-        try!(conn.authenticate());
-        Ok(conn)
+        ConnectionBuilder::new().connect_uds(addr)
     }
 
     /// Creates a Connection object using a TCP socket as the transport.  The addr is the host and
     /// port to connect to.
     pub fn connect_tcp<T: ToSocketAddrs>(addr: T) -> Result<Connection,Error> {
-        let sock = try!(TcpStream::connect(addr));
-        let conn = Connection {
-            sock: RefCell::new(Socket::Tcp(sock)),
-            queue: RefCell::new(VecDeque::new()),
-            serial: RefCell::new(1)
-        };
+        ConnectionBuilder::new().connect_tcp(addr)
+    }
 
-        try!(conn.authenticate());
-        Ok(conn)
+    /// Creates a Connection that runs over stream, which must already be connected to a D-Bus
+    /// server.  This allows any transport, such as a tunnel or an encrypted channel, to be used.
+    /// Read timeouts aren't available on such a Connection, so wait_for_signal can't be given a
+    /// timeout.
+    pub fn from_stream<S: Read + Write + Send + 'static>(stream: S) -> Result<Connection,Error> {
+        ConnectionBuilder::new().from_stream(stream)
     }
 
     fn next_serial(&self) -> u32 {
         let mut serial = self.serial.borrow_mut();
         let current_serial = *serial;
-        *serial = current_serial + 1;
+        // 0 isn't a valid serial, so skip it when the counter wraps
+        *serial = match current_serial.wrapping_add(1) {
+            0 => 1,
+            x => x
+        };
         current_serial
     }
 
+    /// Registers observer to be told about every message sent and received from now on,
+    /// replacing any previous one.  Passing None removes the current observer.
+    pub fn set_observer(&self, observer: Option<Box<MessageObserver + Send>>) {
+        *self.observer.borrow_mut() = observer;
+    }
+
+    /// Returns the serial number that will be given to the next message sent, without using it
+    /// up.
+    pub fn current_serial(&self) -> u32 {
+        *self.serial.borrow()
+    }
+
     fn sock_send(sock: &mut StreamSocket, mbuf: Message) -> Result<(), Error> {
-        let mut msg = Vec::new();
-        mbuf.dbus_encode(&mut msg);
+        try!(sock.write_all(&mbuf.to_bytes()));
+        Ok(())
+    }
+
+    fn sock_send_all(sock: &mut StreamSocket, msgs: Vec<Message>) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(msgs.iter().map(|x| x.wire_len()).sum());
+        for mbuf in msgs {
+            // Each message is aligned relative to its own start, so it can't be encoded
+            // directly onto the end of the previous one
+            buf.extend_from_slice(&mbuf.to_bytes());
+        }
 
-        try!(sock.write_all(&msg));
-        try!(sock.write_all(&mbuf.body));
+        try!(sock.write_all(&buf));
+        Ok(())
+    }
+
+    fn write_msg(&self, mbuf: Message) -> Result<(), Error> {
+        if mbuf.fds().is_empty() {
+            return self.run_sock(move |sock| {
+                Self::sock_send(sock, mbuf)
+            });
+        }
+
+        let mut sock = self.sock.borrow_mut();
+        let sock = match *sock {
+            Socket::Uds(ref mut x) => x,
+            _ => return Err(Error::FdsNotSupported)
+        };
+        let msg = mbuf.to_bytes();
+        let sent = try!(send_with_fds(sock, &msg, mbuf.fds()));
+        try!(sock.write_all(&msg[sent..]));
         Ok(())
     }
 
@@ -421,11 +1038,51 @@ impl Connection {
     pub fn send(&self, mut mbuf: Message) -> Result<u32, Error> {
         let this_serial = self.next_serial();
         mbuf.serial = this_serial;
+        if let Some(ref x) = *self.observer.borrow() {
+            x.on_send(&mbuf);
+        }
+
+        try!(self.write_msg(mbuf));
+        Ok(this_serial)
+    }
+
+    /// Sends several messages over the connection with a single write.  On success, returns the
+    /// serial numbers assigned to the messages, in the same order.
+    pub fn send_all(&self, mut msgs: Vec<Message>) -> Result<Vec<u32>, Error> {
+        let mut serials = Vec::new();
+        for mbuf in msgs.iter_mut() {
+            mbuf.serial = self.next_serial();
+            serials.push(mbuf.serial);
+            if let Some(ref x) = *self.observer.borrow() {
+                x.on_send(mbuf);
+            }
+        }
+
+        // Descriptors have to go along with the start of their own message, so messages carrying
+        // them can't be batched
+        if msgs.iter().any(|x| !x.fds().is_empty()) {
+            for mbuf in msgs {
+                try!(self.write_msg(mbuf));
+            }
+            return Ok(serials);
+        }
 
         try!(self.run_sock(move |sock| {
-            Self::sock_send(sock, mbuf)
+            Self::sock_send_all(sock, msgs)
         }));
-        Ok(this_serial)
+        Ok(serials)
+    }
+
+    /// Sends an error reply to the method call to, addressed to its sender.  error_name is the
+    /// D-Bus error name, such as "org.freedesktop.DBus.Error.InvalidArgs", and message is sent
+    /// as the error's description.
+    pub fn reply_error(&self, to: &Message, error_name: &str, message: &str) -> Result<(),Error> {
+        let mut reply = message::create_error(error_name, to.serial).add_arg(&message);
+        if let Some(sender) = to.get_header(message::HEADER_FIELD_SENDER) {
+            reply = reply.add_header(message::HEADER_FIELD_DESTINATION, sender.clone());
+        }
+        try!(self.send(reply));
+        Ok(())
     }
 
     fn push_queue(&self, queue: &mut VecDeque<Message>) {
@@ -442,32 +1099,136 @@ impl Connection {
     /// for method calls.  Returns the sequence of Value objects that is the body of the method
     /// return.
     ///
-    /// # Panics
     /// Calling this function with a Message for other than METHOD_CALL or with the
-    /// NO_REPLY_EXPECTED flag set is a programming error and will panic.
+    /// NO_REPLY_EXPECTED flag set returns Error::InvalidMessageForCall without sending anything.
     pub fn call_sync(&self, mbuf: Message) -> Result<Option<Vec<Value>>,Error> {
-        assert_eq!(mbuf.message_type, message::MESSAGE_TYPE_METHOD_CALL);
-        assert_eq!(mbuf.flags & message::FLAGS_NO_REPLY_EXPECTED, 0);
+        let msg = try!(self.call_sync_reply(mbuf, None));
+        Ok(try!(msg.get_body()))
+    }
+
+    /// Like call_sync, for methods that return a single a{sv}, such as
+    /// org.freedesktop.DBus.Properties.GetAll.  Returns the dictionary with the variants that
+    /// carried its values unwrapped, or Error::BadData if the reply is anything else.
+    pub fn call_sync_dict(&self, mbuf: Message) -> Result<HashMap<String,Value>,Error> {
+        let mut body = match try!(self.call_sync(mbuf)) {
+            Some(x) => x,
+            None => return Err(Error::BadData)
+        };
+        if body.len() != 1 || body[0].get_signature() != "a{sv}" {
+            return Err(Error::BadData);
+        }
+        let dict = match body.remove(0) {
+            Value::Dictionary(x) => x,
+            _ => return Err(Error::BadData)
+        };
+        let mut map = HashMap::new();
+        for (k, v) in dict.map {
+            match (k, v) {
+                (BasicValue::String(k), Value::Variant(v)) => { map.insert(k, *v.object); },
+                _ => return Err(Error::BadData)
+            }
+        }
+        Ok(map)
+    }
+
+    /// Returns the value of the property name of interface on the object at path, owned by dest,
+    /// converted to T.  Returns Error::UnknownProperty if the object doesn't have the property,
+    /// and Error::BadData if its value can't be converted.
+    pub fn get_property<T: Decodable>(&self, dest: &str, path: &str, interface: &str, name: &str) -> Result<T,Error> {
+        let value = try!(self.get_property_value(dest, path, interface, name));
+        DBusDecoder::decode::<T>(value).or(Err(Error::BadData))
+    }
+
+    /// Like get_property, but returns the property's value without converting it
+    pub(crate) fn get_property_value(&self, dest: &str, path: &str, interface: &str, name: &str) -> Result<Value,Error> {
+        let msg = message::create_method_call(dest, path, "org.freedesktop.DBus.Properties", "Get")
+            .add_arg(&interface)
+            .add_arg(&name);
+        let mut body = match self.call_sync(msg) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Err(Error::BadData),
+            Err(e) => return Err(property_error(e, name))
+        };
+        if body.len() != 1 {
+            return Err(Error::BadData);
+        }
+        match body.remove(0) {
+            Value::Variant(x) => Ok(*x.object),
+            _ => Err(Error::BadData)
+        }
+    }
+
+    /// Sets the property name of interface on the object at path, owned by dest, to value.  The
+    /// value is sent in a variant with value's own signature.  Returns Error::UnknownProperty if
+    /// the object doesn't have the property, and Error::PropertyReadOnly if it can't be set.
+    /// Returns Error::DemarshalError without sending anything if value isn't valid D-Bus data.
+    pub fn set_property<M: Marshal>(&self, dest: &str, path: &str, interface: &str, name: &str, value: M) -> Result<(),Error> {
+        self.set_property_value(dest, path, interface, name, &value)
+    }
+
+    /// Like set_property, for values that aren't Sized
+    pub(crate) fn set_property_value(&self, dest: &str, path: &str, interface: &str, name: &str, value: &Marshal) -> Result<(),Error> {
+        let variant = Variant::new(try!(value.try_to_value()), &value.get_type());
+        let msg = message::create_method_call(dest, path, "org.freedesktop.DBus.Properties", "Set")
+            .add_arg(&interface)
+            .add_arg(&name)
+            .add_arg(&variant);
+        match self.call_sync(msg) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(property_error(e, name))
+        }
+    }
+
+    /// Like call_sync, but gives up and returns Error::Timeout if the reply hasn't arrived
+    /// within timeout.  Messages received while waiting are queued, as with call_sync.  Part of a
+    /// message that had arrived when the timeout expired is kept, and the rest of it is read by
+    /// later calls.
+    pub fn call_sync_timeout(&self, mbuf: Message, timeout: Duration) -> Result<Option<Vec<Value>>,Error> {
+        let msg = try!(self.call_sync_reply(mbuf, Some(Instant::now() + timeout)));
+        Ok(try!(msg.get_body()))
+    }
+
+    /// Like call_sync, but returns the whole reply.  If deadline is given, waits no later than
+    /// that for the reply.
+    fn call_sync_reply(&self, mbuf: Message, deadline: Option<Instant>) -> Result<Message,Error> {
+        if mbuf.message_type != message::MESSAGE_TYPE_METHOD_CALL ||
+           mbuf.flags & message::FLAGS_NO_REPLY_EXPECTED != 0 {
+            return Err(Error::InvalidMessageForCall);
+        }
         let serial = try!(self.send(mbuf));
         // We need a local queue so that read_msg doesn't just give us
         // the same one over and over
         let mut queue = VecDeque::new();
-        loop {
-            let msg = try!(self.read_msg());
-            if let Some(idx) = msg.headers.iter().position(|x| { x.0 == message::HEADER_FIELD_REPLY_SERIAL }) {
-                let obj = {
-                    let x = &msg.headers[idx].1;
-                    x.object.deref().clone()
-                };
-                let reply_serial : u32 = DBusDecoder::decode(obj).unwrap();
-                if reply_serial == serial {
-                    // Move our queued messages into the Connection's queue
-                    self.push_queue(&mut queue);
-                    return Ok(try!(msg.get_body()))
-                };
+        let res = loop {
+            let msg = match self.read_msg_until(deadline) {
+                Ok(x) => x,
+                Err(e) => break Err(e)
             };
+            if msg.reply_serial() == Some(serial) {
+                if msg.message_type == message::MESSAGE_TYPE_ERROR {
+                    break Err(method_error(&msg));
+                }
+                break Ok(msg);
+            }
             queue.push_back(msg);
-        }
+        };
+        // Move our queued messages into the Connection's queue
+        self.push_queue(&mut queue);
+        res
+    }
+
+    /// Adds msg to the end of the queue that read_msg returns messages from.
+    #[cfg(feature = "async")]
+    pub(crate) fn queue_msg(&self, msg: Message) {
+        self.queue.borrow_mut().push_back(msg);
+    }
+
+    /// Removes the reply to the call with the given serial from the queue, if it's there.
+    #[cfg(feature = "async")]
+    pub(crate) fn take_reply(&self, serial: u32) -> Option<Message> {
+        let mut queue = self.queue.borrow_mut();
+        queue.iter().position(|x| x.reply_serial() == Some(serial))
+            .and_then(|x| queue.remove(x))
     }
 
     fn pop_message(&self) -> Option<Message> {
@@ -477,75 +1238,78 @@ impl Connection {
     fn sock_read_msg(sock: &mut StreamSocket) -> Result<Message,Error> {
         let mut buf = Vec::new();
 
-        // Read and demarshal the fixed portion of the header
-        try!(read_exactly(sock, &mut buf, 12));
-        let mut offset = 0;
-        let mut sig = "(yyyyuu)".to_owned();
-        let header = match try!(demarshal(&mut buf, &mut offset, &mut sig)) {
-            Value::Struct(x) => x,
-            x => panic!("Demarshal didn't return what we asked for: {:?}", x)
+        // Read the fixed portion of the header, along with the header field array's length, to
+        // find out how much more there is
+        try!(read_exactly(sock, &mut buf, 16));
+        // The endianness flag decides how everything else is decoded
+        let order = match Endianness::from_byte(buf[0]) {
+            Some(x) => x,
+            None => return Err(Error::BadData)
         };
+        let mut offset = 4;
+        let mut sig = "uuu".to_owned();
+        let mut lens = Vec::new();
+        while !sig.is_empty() {
+            let data = try!(demarshal_with_order(&mut buf, &mut offset, &mut sig, order));
+            lens.push(DBusDecoder::decode::<u32>(data).unwrap() as usize);
+        }
+        let (body_len, arr_len) = (lens[0], lens[2]);
+        // The header field array has the same limit as any other array, so don't let the peer
+        // make us allocate more than that
+        if arr_len > 1 << 26 {
+            return Err(Error::DemarshalError(DemarshalError::ElementTooBig { offset: 12 }));
+        }
 
-        let mut v = header.objects;
-        let mut msg : Message = Default::default();
-        let endian : u8 = DBusDecoder::decode(v.remove(0)).unwrap();
-        if endian == 'B' as u8 {
-            msg.big_endian = true;
-        }
-        msg.message_type = message::MessageType(DBusDecoder::decode(v.remove(0)).unwrap());
-        msg.flags = DBusDecoder::decode::<u8>(v.remove(0)).unwrap();
-        msg.version = DBusDecoder::decode::<u8>(v.remove(0)).unwrap();
-        let body_len = DBusDecoder::decode::<u32>(v.remove(0)).unwrap();
-        msg.serial = DBusDecoder::decode::<u32>(v.remove(0)).unwrap();
-
-        // Read array length
-        try!(read_exactly(sock, &mut buf, 4));
-        // demarshal consumes the buf, so save a copy for when we demarshal the entire array
-        let mut buf_copy = buf.clone();
-        offset = 12;
-        sig = "u".to_owned();
-        let data = demarshal(&mut buf, &mut offset, &mut sig).ok().unwrap();
-        let arr_len = DBusDecoder::decode::<u32>(data).unwrap() as usize;
-
-        // Make buf_copy big enough for the entire array, and fill it
-        buf_copy.reserve(arr_len);
-        if try!(sock.take(arr_len as u64).read_to_end(&mut buf_copy)) != arr_len {
+        // The header is padded to a multiple of 8, and then comes the body
+        let len = align_len(16 + arr_len, 8) + body_len;
+        buf.reserve(len - 16);
+        if try!(sock.take((len - 16) as u64).read_to_end(&mut buf)) != len - 16 {
             return Err(Error::Disconnected);
-        };
-
-        offset = 12;
-        sig = "a(yv)".to_owned();
-        let header_fields = match try!(demarshal(&mut buf_copy, &mut offset, &mut sig)) {
-            Value::Array(x) => x,
-            x => panic!("Demarshal didn't return what we asked for: {:?}", x)
-        };
-
-        msg.headers = Vec::new();
-        for i in header_fields.objects {
-            let mut st = match i {
-                Value::Struct(x) => x,
-                x => panic!("Demarshal didn't return what we asked for: {:?}", x)
-            };
-            let val = st.objects.remove(1);
-            let code = DBusDecoder::decode::<u8>(st.objects.remove(0)).unwrap();
-            let variant = match val {
-                Value::Variant(x) => x,
-                x => panic!("Demarshal didn't return what we asked for: {:?}", x)
-            };
-            msg.headers.push(HeaderField(code, variant));
         }
+        Ok(try!(Message::from_bytes(&buf)))
+    }
 
-        // Read the padding, if any
-        let trailing_pad = 8 - (offset % 8);
-        if trailing_pad % 8 != 0 {
-            try!(read_exactly(sock, &mut buf, trailing_pad));
+    fn read_msg_raw(&self) -> Result<Message,Error> {
+        let msg = try!(self.read_msg_sock());
+        if let Some(ref x) = *self.observer.borrow() {
+            x.on_recv(&msg);
         }
+        Ok(msg)
+    }
 
-        // Finally, read the entire body
-        if body_len > 0 {
-            try!(read_exactly(sock, &mut msg.body, body_len as usize));
+    fn read_msg_sock(&self) -> Result<Message,Error> {
+        let mut sock = self.sock.borrow_mut();
+        let mut pending = self.pending.borrow_mut();
+        let sock = match *sock {
+            Socket::Uds(ref mut x) => x,
+            Socket::Tcp(ref mut x) =>
+                return Self::sock_read_msg(&mut PendingReader { pending: &mut pending, sock: x }),
+            Socket::Other(ref mut x) =>
+                return Self::sock_read_msg(&mut PendingReader { pending: &mut pending, sock: &mut **x }),
+        };
+        let mut fds = self.received_fds.borrow_mut();
+        let res = {
+            let mut reader = FdReader { sock: sock, fds: &mut fds };
+            Self::sock_read_msg(&mut PendingReader { pending: &mut pending, sock: &mut reader })
+        };
+        let res = res.and_then(|msg| Self::attach_fds(msg, &mut fds));
+        if res.is_err() {
+            // There's no telling which message the descriptors that have arrived belong to now
+            close_fds(&mut fds);
         }
+        res
+    }
 
+    /// Moves the descriptors that msg's UNIX_FDS header says it carries from fds to msg.
+    fn attach_fds(mut msg: Message, fds: &mut VecDeque<RawFd>) -> Result<Message,Error> {
+        let count = match msg.get_header(message::HEADER_FIELD_UNIX_FDS) {
+            Some(x) => try!(DBusDecoder::decode::<u32>(x.object.deref().clone()).or(Err(Error::BadData))),
+            None => 0
+        } as usize;
+        if count > fds.len() {
+            return Err(Error::BadData);
+        }
+        msg.set_fds(fds.drain(..count).collect());
         Ok(msg)
     }
 
@@ -553,29 +1317,810 @@ impl Connection {
     pub fn read_msg(&self) -> Result<Message,Error> {
         match self.pop_message() {
             Some(m) => Ok(m),
-            _ => self.run_sock(Self::sock_read_msg)
+            _ => self.read_msg_raw()
         }
     }
-}
 
-#[cfg(test)]
-fn validate_connection(conn: &mut Connection) {
-    let msg = message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
-                                          "org.freedesktop.DBus", "ListNames");
+    /// Returns a message if a whole one is available without blocking, or None if not.  Data that
+    /// has arrived so far is kept, so a message that arrives in pieces is returned once the last
+    /// of it does.  This reads everything that's available, which can include more than one
+    /// message, so callers waiting for the socket to become readable should call this until it
+    /// returns None before waiting again.
+    pub fn try_read_msg(&self) -> Result<Option<Message>,Error> {
+        if let Some(m) = self.pop_message() {
+            return Ok(Some(m));
+        }
+        self.try_read_msg_raw()
+    }
+
+    /// Like try_read_msg, but only looks at the socket, not at the queue.
+    pub(crate) fn try_read_msg_raw(&self) -> Result<Option<Message>,Error> {
+        try!(self.set_nonblocking(true));
+        let res = self.read_pending();
+        try!(self.set_nonblocking(false));
+        let eof = try!(res);
+
+        if try!(self.pending_complete()) {
+            // The whole message is buffered, so this doesn't touch the socket
+            return self.read_msg_raw().map(Some);
+        }
+        if eof {
+            return Err(Error::Disconnected);
+        }
+        Ok(None)
+    }
+
+    /// Returns whether the pending buffer holds at least one whole message.
+    fn pending_complete(&self) -> Result<bool,Error> {
+        match framed_message_len(&self.pending.borrow()) {
+            Ok(_) => Ok(true),
+            Err(DemarshalError::MessageTooShort { .. }) => Ok(false),
+            Err(e) => {
+                close_fds(&mut self.received_fds.borrow_mut());
+                Err(Error::DemarshalError(e))
+            },
+        }
+    }
+
+    /// Does a single read from the socket into the pending buffer, and returns how much was read.
+    fn read_chunk(&self) -> io::Result<usize> {
+        let mut sock = self.sock.borrow_mut();
+        let mut fds = self.received_fds.borrow_mut();
+        let mut buf = [0; 4096];
+        let len = try!(match *sock {
+            Socket::Uds(ref mut x) => recv_with_fds(x, &mut buf, &mut fds),
+            Socket::Tcp(ref mut x) => x.read(&mut buf),
+            Socket::Other(ref mut x) => x.read(&mut buf),
+        });
+        self.pending.borrow_mut().extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Moves whatever data is available on the non-blocking socket into the pending buffer.
+    /// Returns whether the peer has closed the connection.
+    fn read_pending(&self) -> Result<bool,Error> {
+        loop {
+            match self.read_chunk() {
+                Ok(0) => return Ok(true),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(Error::IOError(e)),
+            }
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(),Error> {
+        let sock = self.sock.borrow();
+
+        try!(match *sock {
+            Socket::Tcp(ref x) => x.set_nonblocking(nonblocking),
+            Socket::Uds(ref x) => x.set_nonblocking(nonblocking),
+            Socket::Other(_) => Err(io::Error::new(io::ErrorKind::Other,
+                                                   "stream doesn't support non-blocking reads")),
+        });
+        Ok(())
+    }
+
+    /// Returns the names that the bus can start on demand, whether or not they are currently
+    /// owned.
+    pub fn list_activatable_names(&self) -> Result<Vec<String>,Error> {
+        let msg = create_driver_call("ListActivatableNames");
+        match try!(self.call_sync(msg)) {
+            Some(ref x) if x.len() == 1 => {
+                DBusDecoder::decode::<Vec<String>>(x[0].clone()).or(Err(Error::BadData))
+            },
+            _ => Err(Error::BadData)
+        }
+    }
+
+    /// Returns the bus's globally unique ID.
+    pub fn get_id(&self) -> Result<String,Error> {
+        let msg = create_driver_call("GetId");
+        match try!(self.call_sync(msg)) {
+            Some(ref x) if x.len() == 1 => {
+                DBusDecoder::decode::<String>(x[0].clone()).or(Err(Error::BadData))
+            },
+            _ => Err(Error::BadData)
+        }
+    }
+
+    /// Asks the bus to send this connection the messages that match rule, such as signals it
+    /// would otherwise not receive.  A MatchRule can be used to build the rule.
+    pub fn add_match(&self, rule: &str) -> Result<(),Error> {
+        try!(self.call_sync(create_driver_call("AddMatch").add_arg(&rule)));
+        Ok(())
+    }
+
+    /// Removes a rule that was added with add_match.  rule must be the same as the string that
+    /// was added.
+    pub fn remove_match(&self, rule: &str) -> Result<(),Error> {
+        try!(self.call_sync(create_driver_call("RemoveMatch").add_arg(&rule)));
+        Ok(())
+    }
+
+    /// Asks the bus to give this connection the well-known name, e.g.
+    /// `RequestNameFlags::REPLACE_EXISTING | RequestNameFlags::DO_NOT_QUEUE`.
+    pub fn request_name(&self, name: &str, flags: RequestNameFlags) -> Result<RequestNameReply,Error> {
+        let msg = create_driver_call("RequestName")
+            .add_arg(&name)
+            .add_arg(&flags.0);
+        match try!(self.call_sync(msg)) {
+            Some(ref x) if x.len() == 1 => {
+                match DBusDecoder::decode::<u32>(x[0].clone()) {
+                    Ok(1) => Ok(RequestNameReply::PrimaryOwner),
+                    Ok(2) => Ok(RequestNameReply::InQueue),
+                    Ok(3) => Ok(RequestNameReply::Exists),
+                    Ok(4) => Ok(RequestNameReply::AlreadyOwner),
+                    _ => Err(Error::BadData)
+                }
+            },
+            _ => Err(Error::BadData)
+        }
+    }
+
+    /// Asks the bus to start the service that provides name, if it isn't already running.
+    pub fn start_service(&self, name: &str) -> Result<StartServiceReply,Error> {
+        let msg = create_driver_call("StartServiceByName")
+            .add_arg(&name)
+            .add_arg(&(0 as u32));
+        match try!(self.call_sync(msg)) {
+            Some(ref x) if x.len() == 1 => {
+                match DBusDecoder::decode::<u32>(x[0].clone()) {
+                    Ok(1) => Ok(StartServiceReply::Started),
+                    Ok(2) => Ok(StartServiceReply::AlreadyRunning),
+                    _ => Err(Error::BadData)
+                }
+            },
+            _ => Err(Error::BadData)
+        }
+    }
+
+    /// Checks that dest is alive by calling org.freedesktop.DBus.Peer.Ping on it.  Returns
+    /// Error::MethodError if dest replies with an error, or if the bus does because dest doesn't
+    /// exist.
+    pub fn ping(&self, dest: &str) -> Result<(),Error> {
+        let msg = message::create_method_call(dest, "/", "org.freedesktop.DBus.Peer", "Ping");
+        let reply = try!(self.call_sync_reply(msg, None));
+        if reply.message_type != message::MESSAGE_TYPE_METHOD_RETURN {
+            return Err(Error::BadData);
+        }
+        Ok(())
+    }
+
+    fn read_msg_until(&self, deadline: Option<Instant>) -> Result<Message,Error> {
+        if let Some(m) = self.pop_message() {
+            return Ok(m);
+        }
+        let deadline = match deadline {
+            Some(x) => x,
+            None => return self.read_msg()
+        };
+        // Collect the message in the pending buffer, so that running out of time part way through
+        // it doesn't lose what has been read
+        loop {
+            if try!(self.pending_complete()) {
+                // The whole message is buffered, so this doesn't touch the socket
+                return self.read_msg_raw();
+            }
+            // Each read only gets the time that's left, so a peer that sends a little at a time
+            // can't hold us past the deadline
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            try!(self.set_read_timeout(Some(deadline - now)));
+            let res = self.read_chunk();
+            try!(self.set_read_timeout(None));
+            match res {
+                Ok(0) => return Err(Error::Disconnected),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut ||
+                              e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(Error::IOError(e)),
+            }
+        }
+    }
+
+    /// Blocks until a signal with the given interface and member comes in, and returns it.  Any
+    /// other messages received in the meantime are queued, and will be returned by later calls to
+    /// read_msg.
+    ///
+    /// The bus only delivers broadcast signals that match a rule installed with
+    /// org.freedesktop.DBus.AddMatch, so the caller must install one before waiting.  If timeout
+    /// elapses before the signal arrives, returns Error::Timeout.
+    pub fn wait_for_signal(&self, interface: &str, member: &str, timeout: Option<Duration>) -> Result<Message,Error> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        let interface = Value::from(interface);
+        let member = Value::from(member);
+        let mut queue = VecDeque::new();
+        let res = loop {
+            let msg = match self.read_msg_until(deadline) {
+                Ok(x) => x,
+                Err(e) => break Err(e)
+            };
+            if msg.message_type == message::MESSAGE_TYPE_SIGNAL &&
+               msg.get_header(message::HEADER_FIELD_INTERFACE).map(|x| &*x.object) == Some(&interface) &&
+               msg.get_header(message::HEADER_FIELD_MEMBER).map(|x| &*x.object) == Some(&member) {
+                break Ok(msg);
+            }
+            queue.push_back(msg);
+        };
+        self.push_queue(&mut queue);
+        res
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Descriptors that arrived without a message to claim them
+        close_fds(&mut self.received_fds.borrow_mut());
+        // Stop waiting on the socket before it's closed
+        #[cfg(feature = "async")]
+        self.async_state.borrow_mut().stop();
+    }
+}
+
+impl AsRawFd for Connection {
+    /// Returns the file descriptor of the underlying socket, so that it can be waited on with
+    /// poll or an event loop.  The socket is still owned by the Connection, and mustn't be closed
+    /// or read from directly.  Messages the Connection has already queued, e.g. while waiting
+    /// for a reply in call_sync, don't make the socket readable, so callers should check for them
+    /// too.  Returns -1 for a Connection created with from_stream.
+    fn as_raw_fd(&self) -> RawFd {
+        match *self.sock.borrow() {
+            Socket::Tcp(ref x) => x.as_raw_fd(),
+            Socket::Uds(ref x) => x.as_raw_fd(),
+            Socket::Other(_) => -1,
+        }
+    }
+}
+
+impl ConnectionBuilder {
+    /// Returns a builder with the default options
+    pub fn new() -> ConnectionBuilder {
+        Default::default()
+    }
+
+    /// If skip is true, the connection is authenticated but the Hello message isn't sent.  Such a
+    /// connection has no unique name, and the bus won't route messages for it, until
+    /// Connection::hello is called.
+    pub fn skip_hello(mut self, skip: bool) -> ConnectionBuilder {
+        self.skip_hello = skip;
+        self
+    }
+
+    /// If skip is true, the GUID sent by the server during authentication isn't checked against
+    /// the guid option of the address being connected to.  By default, a connection to an address
+    /// with a guid fails with Error::GuidMismatch if the server turns out to be a different one.
+    pub fn skip_guid_check(mut self, skip: bool) -> ConnectionBuilder {
+        self.skip_guid_check = skip;
+        self
+    }
+
+    /// If nodelay is true, TCP connections are made with TCP_NODELAY set.  Has no effect on other
+    /// transports.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> ConnectionBuilder {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// If keepalive is true, TCP connections are made with SO_KEEPALIVE set.  Has no effect on
+    /// other transports.
+    pub fn tcp_keepalive(mut self, keepalive: bool) -> ConnectionBuilder {
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    /// Binds TCP connections to the local address addr before connecting.  Use port 0 to let the
+    /// system pick the port.  Has no effect on other transports.
+    pub fn tcp_bind_addr(mut self, addr: SocketAddr) -> ConnectionBuilder {
+        self.tcp_bind_addr = Some(addr);
+        self
+    }
+
+    fn tcp_connect<T: ToSocketAddrs>(&self, addr: T) -> io::Result<TcpStream> {
+        let local = match self.tcp_bind_addr {
+            Some(ref x) => x,
+            None => return TcpStream::connect(addr)
+        };
+        let mut last_err = None;
+        for remote in try!(addr.to_socket_addrs()) {
+            match tcp_connect_from(local, &remote) {
+                Ok(x) => return Ok(x),
+                Err(e) => last_err = Some(e)
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+        }))
+    }
+
+    fn setup(&self, sock: Socket, guid: Option<&str>) -> Result<Connection,Error> {
+        if let Socket::Tcp(ref x) = sock {
+            if self.tcp_nodelay {
+                try!(x.set_nodelay(true));
+            }
+            if self.tcp_keepalive {
+                try!(set_keepalive(x, true));
+            }
+        }
+        let conn = Connection::new(sock);
+        try!(conn.authenticate());
+        if let Some(guid) = guid {
+            if !self.skip_guid_check && conn.server_guid().as_ref().map(|x| &x[..]) != Some(guid) {
+                return Err(Error::GuidMismatch);
+            }
+        }
+        if !self.skip_hello {
+            try!(conn.hello());
+        }
+        Ok(conn)
+    }
+
+    fn connect_addr(&self, addr: ServerAddress) -> Result<Connection,Error> {
+        match addr {
+            ServerAddress::Unix(unix) => {
+                let sock = try!(UnixStream::connect(unix.path()));
+                self.setup(Socket::Uds(sock), unix.guid())
+            },
+            ServerAddress::Tcp(tcp) => {
+                let sock = try!(self.tcp_connect(&tcp));
+                self.setup(Socket::Tcp(sock), tcp.guid())
+            },
+            ServerAddress::NonceTcp(tcp) => {
+                let nonce = try!(read_nonce(tcp.noncefile()));
+                let mut sock = try!(self.tcp_connect(&tcp));
+                // The nonce has to come before anything else, even the NUL byte
+                try!(sock.write_all(&nonce));
+                self.setup(Socket::Tcp(sock), tcp.guid())
+            },
+        }
+    }
+
+    /// Connects to a DBus address string.  See Connection::connect.
+    pub fn connect(&self, addr: &str) -> Result<Connection, Error> {
+        let mut last_err = None;
+        for addr in addr.split(';').filter(|x| !x.is_empty()) {
+            let res = match ServerAddress::from_str(addr) {
+                Ok(x) => self.connect_addr(x),
+                Err(e) => Err(Error::from(e)),
+            };
+            match res {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            Error::AddressError((address::Error::BadTransportSeparator, addr.to_owned()))
+        }))
+    }
+
+    /// Connects to the system bus.  See Connection::connect_system.
+    pub fn connect_system(&self) -> Result<Connection, Error> {
+        let default = "unix:path=/var/run/dbus/system_bus_socket";
+        if let Ok(e) = env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+            self.connect(&e)
+        } else {
+            self.connect(default)
+        }
+    }
+
+    /// Connects to the session bus.  See Connection::connect_session.
+    pub fn connect_session(&self) -> Result<Connection, Error> {
+        if let Ok(e) = env::var("DBUS_SESSION_BUS_ADDRESS") {
+            self.connect(&e)
+        } else {
+            self.connect_session_fallback()
+        }
+    }
+
+    /// Connects to the session bus at its well-known location, for when no address was given
+    fn connect_session_fallback(&self) -> Result<Connection, Error> {
+        let path = match env::var_os("XDG_RUNTIME_DIR") {
+            Some(x) => PathBuf::from(x).join("bus"),
+            None => return Err(Error::NoEnvironment)
+        };
+        if !path.exists() {
+            return Err(Error::NoEnvironment);
+        }
+        self.connect_uds(path)
+    }
+
+    /// Connects using a UNIX domain socket.  See Connection::connect_uds.
+    pub fn connect_uds<P: AsRef<Path>>(&self, addr: P) -> Result<Connection,Error> {
+        let sock = try!(UnixStream::connect(addr));
+        self.setup(Socket::Uds(sock), None)
+    }
+
+    /// Connects using a TCP socket.  See Connection::connect_tcp.
+    pub fn connect_tcp<T: ToSocketAddrs>(&self, addr: T) -> Result<Connection,Error> {
+        let sock = try!(self.tcp_connect(addr));
+        self.setup(Socket::Tcp(sock), None)
+    }
+
+    /// Connects over a caller-provided stream.  See Connection::from_stream.
+    pub fn from_stream<S: Read + Write + Send + 'static>(&self, stream: S) -> Result<Connection,Error> {
+        self.setup(Socket::Other(Box::new(stream)), None)
+    }
+}
+
+/// A socket that reads from a canned buffer and records everything written to it
+#[cfg(test)]
+struct FakeSocket {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FakeSocket {
+    fn new(input: &[u8]) -> FakeSocket {
+        FakeSocket { input: io::Cursor::new(input.to_vec()), output: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+impl Read for FakeSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for FakeSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn validate_connection(conn: &mut Connection) {
+    let msg = message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                          "org.freedesktop.DBus", "ListNames");
     let resp = conn.call_sync(msg).unwrap();
     println!("ListNames: {:?}", resp);
 }
 
+#[test]
+#[cfg(feature = "cookie-auth")]
+fn test_auth_cookie_rejected() {
+    use std::fs;
+    use std::process;
+
+    let keyring = env::temp_dir().join(format!("dbus-bytestream-keyring-{}", process::id()));
+    fs::create_dir_all(&keyring).unwrap();
+    let mut f = File::create(keyring.join("org_freedesktop_general")).unwrap();
+    f.write_all(b"1 1500000000 0123456789abcdef\n").unwrap();
+
+    let challenge = b"org_freedesktop_general 1 0badc0de".to_hex();
+    let data = "DATA ".to_owned() + &challenge + "\r\n";
+
+    let mut sock = FakeSocket::new((data.clone() + "REJECTED DBUS_COOKIE_SHA1 ANONYMOUS\r\n").as_bytes());
+    match Connection::sock_auth_cookie_keyring(&mut sock, &keyring) {
+        Err(Error::AuthRejected) => (),
+        x => panic!("Expected AuthRejected, got {:?}", x)
+    };
+    let output = String::from_utf8(sock.output).unwrap();
+    assert!(output.starts_with("AUTH DBUS_COOKIE_SHA1 "));
+    assert!(!output.contains("BEGIN"));
+
+    let mut sock = FakeSocket::new((data + "OK 1234deadbeef\r\n").as_bytes());
+    assert_eq!(Connection::sock_auth_cookie_keyring(&mut sock, &keyring).unwrap(), "1234deadbeef");
+    assert!(String::from_utf8(sock.output).unwrap().ends_with("BEGIN\r\n"));
+
+    // A server that doesn't offer the mechanism at all rejects it right away
+    let mut sock = FakeSocket::new(b"REJECTED EXTERNAL\r\n");
+    match Connection::sock_auth_cookie_keyring(&mut sock, &keyring) {
+        Err(Error::AuthRejected) => (),
+        x => panic!("Expected AuthRejected, got {:?}", x)
+    };
+
+    fs::remove_dir_all(&keyring).unwrap();
+}
+
+#[test]
+fn test_generate_guid() {
+    let guid = generate_guid();
+    assert_eq!(guid.len(), 32);
+    assert!(guid.chars().all(|c| c.is_digit(16) && !c.is_uppercase()));
+    assert!(generate_guid() != guid);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let stamp = u64::from_str_radix(&guid[24..], 16).unwrap();
+    assert!(stamp <= now && stamp + 60 > now);
+}
+
+#[test]
+fn test_read_line_limit() {
+    let mut sock = FakeSocket::new(b"OK 1234\r\n");
+    assert_eq!(read_line(&mut sock).unwrap(), "OK 1234\r\n");
+
+    let long = vec![b'a'; MAX_AUTH_LINE + 1];
+    let mut sock = FakeSocket::new(&long);
+    match read_line(&mut sock) {
+        Err(Error::AuthFailed) => (),
+        x => panic!("Expected AuthFailed, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_pair() {
+    use std::thread;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        assert_eq!(call.get_body().unwrap().unwrap(), vec![Value::from("ping")]);
+        let reply = message::create_reply(&call).add_arg(&"pong");
+        server.send(reply).unwrap();
+    });
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Ping")
+        .add_arg(&"ping");
+    assert_eq!(client.call_sync(msg).unwrap().unwrap(), vec![Value::from("pong")]);
+    assert_eq!(client.unique_name(), None);
+    assert_eq!(client.auth_mechanism(), AuthMechanism::Unauthenticated);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_send_large() {
+    use std::thread;
+
+    let (client, server) = Connection::pair().unwrap();
+    let data = vec![0xa5 as u8; 1 << 20];
+    let msg = message::create_signal("/com/test", "com.test", "Large").add_arg(&data);
+    let expected = msg.body.clone();
+    let handle = thread::spawn(move || {
+        for _ in 0..10 {
+            let msg = server.read_msg().unwrap();
+            assert_eq!(msg.body, expected);
+        }
+    });
+
+    for _ in 0..10 {
+        client.send(msg.clone()).unwrap();
+    }
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_read_pipelined() {
+    let first = message::create_method_call("com.test", "/com/test", "com.test", "First")
+        .add_arg(&"abc");
+    let second = message::create_signal("/com/test", "com.test", "Second")
+        .add_arg(&(7 as u8));
+    let mut data = Vec::new();
+    for msg in vec![&first, &second] {
+        let mut buf = Vec::new();
+        msg.dbus_encode(&mut buf);
+        data.extend_from_slice(&buf);
+        data.extend_from_slice(&msg.body);
+    }
+
+    let mut sock = FakeSocket::new(&data);
+    let msg = Connection::sock_read_msg(&mut sock).unwrap();
+    assert_eq!(msg.message_type, message::MESSAGE_TYPE_METHOD_CALL);
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("abc")]);
+    let msg = Connection::sock_read_msg(&mut sock).unwrap();
+    assert_eq!(msg.message_type, message::MESSAGE_TYPE_SIGNAL);
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from(7 as u8)]);
+    match Connection::sock_read_msg(&mut sock) {
+        Err(Error::Disconnected) => (),
+        x => panic!("Expected Disconnected, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_read_big_endian() {
+    // A signal from a big-endian peer, with a signature header of "u" and a single u32 argument
+    let data = vec![
+        b'B', 4, 0, 1,
+        0, 0, 0, 4,
+        0, 0, 0, 42,
+        0, 0, 0, 7,
+        8, 1, b'g', 0, 1, b'u', 0, 0,
+        1, 2, 3, 4,
+    ];
+    let mut sock = FakeSocket::new(&data);
+    let msg = Connection::sock_read_msg(&mut sock).unwrap();
+    assert!(msg.big_endian);
+    assert_eq!(msg.message_type, message::MESSAGE_TYPE_SIGNAL);
+    assert_eq!(msg.serial, 42);
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from(0x01020304 as u32)]);
+}
+
+#[test]
+fn test_send_big_endian() {
+    let (client, server) = Connection::pair().unwrap();
+    let msg = message::create_signal("/com/test", "com.test", "Foo")
+        .with_endianness(Endianness::Big)
+        .add_arg(&"hello")
+        .add_arg(&vec![1 as u32, 2, 3]);
+    client.send(msg).unwrap();
+
+    let msg = server.read_msg().unwrap();
+    assert!(msg.big_endian);
+    assert_eq!(msg.get_body().unwrap().unwrap(),
+               vec![Value::from("hello"), vec![1 as u32, 2, 3].to_value()]);
+}
+
+#[test]
+fn test_custom_header() {
+    let (client, server) = Connection::pair().unwrap();
+    let mut msg = message::create_signal("/com/test", "com.test", "Foo");
+    msg.set_header_value(42, Value::from("custom"), "s");
+    client.send(msg).unwrap();
+
+    let msg = server.read_msg().unwrap();
+    assert_eq!(msg.header_value(42), Some(&Value::from("custom")));
+    assert_eq!(msg.get_header(42).unwrap().signature.0, "s");
+}
+
+#[test]
+fn test_large_header() {
+    use std::time::Instant;
+
+    let long = "x".repeat(1 << 20);
+    let mut msg = message::create_signal("/com/test", "com.test", "Foo");
+    msg.set_header_value(42, Value::from(&long[..]), "s");
+    let mut data = Vec::new();
+    msg.dbus_encode(&mut data);
+
+    let start = Instant::now();
+    let mut sock = FakeSocket::new(&data);
+    let msg = Connection::sock_read_msg(&mut sock).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(msg.header_value(42), Some(&Value::from(&long[..])));
+
+    // A header field array longer than any array may be is refused before it's read
+    let mut data = data[..16].to_vec();
+    data[12..16].copy_from_slice(&[0, 0, 0, 5]);
+    let mut sock = FakeSocket::new(&data);
+    match Connection::sock_read_msg(&mut sock) {
+        Err(Error::DemarshalError(DemarshalError::ElementTooBig { offset: 12 })) => (),
+        x => panic!("Expected ElementTooBig, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_current_serial() {
+    let (conn, _peer) = Connection::pair().unwrap();
+    assert_eq!(conn.current_serial(), 1);
+    assert_eq!(conn.send(message::create_signal("/com/test", "com.test", "Foo")).unwrap(), 1);
+    assert_eq!(conn.current_serial(), 2);
+
+    *conn.serial.borrow_mut() = u32::max_value();
+    assert_eq!(conn.send(message::create_signal("/com/test", "com.test", "Foo")).unwrap(), u32::max_value());
+    assert_eq!(conn.current_serial(), 1);
+    assert_eq!(conn.send(message::create_signal("/com/test", "com.test", "Foo")).unwrap(), 1);
+}
+
+#[test]
+fn test_pass_fd() {
+    use std::fs::File;
+
+    let (client, server) = Connection::pair().unwrap();
+    let mut pipe = [0; 2];
+    assert_eq!(unsafe { libc::pipe(pipe.as_mut_ptr()) }, 0);
+    let mut writer = unsafe { File::from_raw_fd(pipe[1]) };
+
+    let msg = message::create_signal("/com/test", "com.test", "Fd")
+        .add_arg(&"hello")
+        .add_fd(pipe[0]);
+    client.send(msg).unwrap();
+
+    let mut msg = server.read_msg().unwrap();
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("hello")]);
+    let fd = msg.take_fd(0).unwrap();
+    assert_eq!(msg.take_fd(0), None);
+    assert_eq!(msg.take_fd(1), None);
+    drop(msg);
+
+    // The received descriptor is still open, and refers to the same pipe
+    writer.write_all(b"data").unwrap();
+    drop(writer);
+    let mut reader = unsafe { File::from_raw_fd(fd) };
+    let mut data = String::new();
+    reader.read_to_string(&mut data).unwrap();
+    assert_eq!(data, "data");
+}
+
+#[test]
+fn test_pass_fd_truncated() {
+    let (a, b) = UnixStream::pair().unwrap();
+    // More descriptors than fit in recv_with_fds's control buffer
+    let fd = unsafe { libc::dup(0) };
+    let sent = vec![fd; 253];
+    send_with_fds(&a, b"x", &sent).unwrap();
+    unsafe { libc::close(fd); }
+
+    let mut fds = VecDeque::new();
+    let mut buf = [0; 16];
+    match recv_with_fds(&b, &mut buf, &mut fds) {
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => (),
+        x => panic!("Expected InvalidData, got {:?}", x)
+    };
+    assert!(fds.is_empty());
+}
+
+#[test]
+fn test_pass_fd_tcp() {
+    use std::net::TcpListener;
+
+    // Descriptors can't be sent over TCP
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (_peer, _) = listener.accept().unwrap();
+    let conn = Connection::new(Socket::Tcp(stream));
+    let fd = unsafe { libc::dup(0) };
+    let msg = message::create_signal("/com/test", "com.test", "Fd").add_fd(fd);
+    match conn.send(msg) {
+        Err(Error::FdsNotSupported) => (),
+        x => panic!("Expected FdsNotSupported, got {:?}", x)
+    };
+}
+
 #[test]
 fn test_connect_system() {
     let mut conn = Connection::connect_system().unwrap();
     validate_connection(&mut conn);
 }
 
+#[test]
+fn test_connect_list() {
+    let addr = env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+    let conn = Connection::connect(&("unix:path=/nonexistent/bus;tcp:bogus;".to_owned() + &addr))
+        .unwrap();
+    conn.ping("org.freedesktop.DBus").unwrap();
+
+    match Connection::connect("tcp:bogus;unix:path=/nonexistent/bus") {
+        Err(Error::IOError(_)) => (),
+        x => panic!("Expected IOError, got {:?}", x.err())
+    };
+    match Connection::connect(";") {
+        Err(Error::AddressError(_)) => (),
+        x => panic!("Expected AddressError, got {:?}", x.err())
+    };
+}
+
+#[test]
+fn test_connect_session_fallback() {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    let addr = env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+    let bus = match ServerAddress::from_str(&addr).unwrap() {
+        ServerAddress::Unix(x) => x.path().to_owned(),
+        x => panic!("Expected a unix address, got {:?}", x)
+    };
+    let dir = env::temp_dir().join(format!("dbus-bytestream-runtime-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    symlink(&bus, dir.join("bus")).unwrap();
+
+    // Other tests need DBUS_SESSION_BUS_ADDRESS, so rather than unsetting it, go straight to
+    // what connect_session does without it
+    env::set_var("XDG_RUNTIME_DIR", &dir);
+    let res = ConnectionBuilder::new().connect_session_fallback();
+    fs::remove_dir_all(&dir).unwrap();
+    res.unwrap().ping("org.freedesktop.DBus").unwrap();
+
+    match ConnectionBuilder::new().connect_session_fallback() {
+        Err(Error::NoEnvironment) => (),
+        x => panic!("Expected NoEnvironment, got {:?}", x.err())
+    };
+}
+
 #[test]
 fn test_connect_session() {
     let mut conn = Connection::connect_session().unwrap();
     validate_connection(&mut conn);
+    assert_eq!(conn.auth_mechanism(), AuthMechanism::External);
     let mut msg = message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
                                               "org.freedesktop.DBus", "RequestName");
     msg = msg.add_arg(&"com.test.foobar")
@@ -587,6 +2132,674 @@ fn test_connect_session() {
     assert_eq!(value, Value::from(1 as u32));
 }
 
+#[test]
+fn test_request_name() {
+    let flags = RequestNameFlags::REPLACE_EXISTING | RequestNameFlags::DO_NOT_QUEUE;
+    assert_eq!(flags, RequestNameFlags(6));
+
+    let first = Connection::connect_session().unwrap();
+    let second = Connection::connect_session().unwrap();
+    assert_eq!(first.request_name("com.test.requestname", RequestNameFlags::default()).unwrap(),
+               RequestNameReply::PrimaryOwner);
+    assert_eq!(first.request_name("com.test.requestname", RequestNameFlags::default()).unwrap(),
+               RequestNameReply::AlreadyOwner);
+    // first didn't allow replacement, so second can't take the name
+    assert_eq!(second.request_name("com.test.requestname", flags).unwrap(),
+               RequestNameReply::Exists);
+    assert_eq!(second.request_name("com.test.requestname", RequestNameFlags::default()).unwrap(),
+               RequestNameReply::InQueue);
+}
+
+#[test]
+fn test_call_sync_invalid() {
+    let mut conn = Connection::connect_session().unwrap();
+    let mut msg = message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                              "org.freedesktop.DBus", "ListNames");
+    msg.flags |= message::FLAGS_NO_REPLY_EXPECTED;
+    match conn.call_sync(msg) {
+        Err(Error::InvalidMessageForCall) => (),
+        x => panic!("Expected InvalidMessageForCall, got {:?}", x)
+    };
+
+    let msg = message::create_signal("/com/test", "com.test", "Foo");
+    match conn.call_sync(msg) {
+        Err(Error::InvalidMessageForCall) => (),
+        x => panic!("Expected InvalidMessageForCall, got {:?}", x)
+    };
+
+    // The connection is still usable afterwards
+    validate_connection(&mut conn);
+}
+
+#[test]
+fn test_reply_round_trip() {
+    use std::thread;
+
+    let server = Connection::connect_session().unwrap();
+    server.request_name("com.test.replyserver", Default::default()).unwrap();
+
+    let handle = thread::spawn(move || {
+        loop {
+            let call = server.read_msg().unwrap();
+            if call.message_type != message::MESSAGE_TYPE_METHOD_CALL {
+                continue;
+            }
+            let reply = message::create_reply(&call).add_arg(&"pong");
+            server.send(reply).unwrap();
+            return;
+        }
+    });
+
+    let client = Connection::connect_session().unwrap();
+    let msg = message::create_method_call("com.test.replyserver", "/com/test",
+                                          "com.test", "Ping");
+    let resp = client.call_sync(msg).unwrap().unwrap();
+    assert_eq!(resp, vec![Value::from("pong")]);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_reply_error() {
+    use std::thread;
+
+    let server = Connection::connect_session().unwrap();
+    server.request_name("com.test.errorserver", Default::default()).unwrap();
+
+    let handle = thread::spawn(move || {
+        loop {
+            let call = server.read_msg().unwrap();
+            if call.message_type != message::MESSAGE_TYPE_METHOD_CALL {
+                continue;
+            }
+            server.reply_error(&call, "com.test.Error.Failed", "it broke").unwrap();
+            return;
+        }
+    });
+
+    let client = Connection::connect_session().unwrap();
+    let msg = message::create_method_call("com.test.errorserver", "/com/test",
+                                          "com.test", "Fail");
+    match client.call_sync(msg) {
+        Err(Error::MethodError { name, message }) => {
+            assert_eq!(name, "com.test.Error.Failed");
+            assert_eq!(message, Some("it broke".to_owned()));
+        },
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_match_rule() {
+    let rule = MatchRule::new().sender("com.test").interface("com.test.Iface").member("Foo")
+        .path("/com/test");
+    assert_eq!(rule.to_string(), "type='signal',sender='com.test',interface='com.test.Iface',\
+                                  member='Foo',path='/com/test'");
+    assert_eq!(MatchRule::new().to_string(), "type='signal'");
+    // Setting a key again replaces it
+    assert_eq!(MatchRule::new().member("Foo").member("Bar").to_string(),
+               "type='signal',member='Bar'");
+    assert_eq!(MatchRule::new().member("it's").to_string(), "type='signal',member='it'\\''s'");
+
+    let (conn, peer) = Connection::pair().unwrap();
+    let rule = rule.to_string();
+    let handle = {
+        let rule = rule.clone();
+        ::std::thread::spawn(move || {
+            for method in vec!["AddMatch", "RemoveMatch"] {
+                let call = peer.read_msg().unwrap();
+                assert_eq!(call.header_value(message::HEADER_FIELD_MEMBER), Some(&Value::from(method)));
+                assert_eq!(call.header_value(message::HEADER_FIELD_INTERFACE),
+                           Some(&Value::from("org.freedesktop.DBus")));
+                assert_eq!(call.get_body().unwrap().unwrap(), vec![Value::from(&rule[..])]);
+                peer.send(message::create_reply(&call)).unwrap();
+            }
+        })
+    };
+    conn.add_match(&rule).unwrap();
+    conn.remove_match(&rule).unwrap();
+    handle.join().unwrap();
+
+    // The bus only removes rules that were added
+    let conn = Connection::connect_session().unwrap();
+    conn.add_match(&rule).unwrap();
+    conn.remove_match(&rule).unwrap();
+    assert!(conn.remove_match(&rule).is_err());
+}
+
+#[test]
+fn test_wait_for_signal() {
+    let conn = Connection::connect_session().unwrap();
+    conn.add_match(&MatchRule::new().interface("com.test.wait").to_string()).unwrap();
+
+    let emitter = Connection::connect_session().unwrap();
+    emitter.send(message::create_signal("/com/test", "com.test.wait", "Other")).unwrap();
+    emitter.send(message::create_signal("/com/test", "com.test.wait", "Done").add_arg(&"x")).unwrap();
+
+    let sig = conn.wait_for_signal("com.test.wait", "Done", None).unwrap();
+    assert_eq!(sig.get_body().unwrap().unwrap(), vec![Value::from("x")]);
+
+    // The signal we weren't waiting for is still available
+    loop {
+        let msg = conn.read_msg().unwrap();
+        if msg.get_header(message::HEADER_FIELD_MEMBER).map(|x| &*x.object) == Some(&Value::from("Other")) {
+            break;
+        }
+    }
+
+    match conn.wait_for_signal("com.test.wait", "Never", Some(Duration::from_millis(100))) {
+        Err(Error::Timeout) => (),
+        x => panic!("Expected Timeout, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_try_read_msg() {
+    let (mut sock, peer) = UnixStream::pair().unwrap();
+    let conn = Connection::new(Socket::Uds(peer));
+    assert!(conn.try_read_msg().unwrap().is_none());
+
+    let msg = message::create_signal("/com/test", "com.test", "Foo").add_arg(&"hello");
+    let mut data = Vec::new();
+    msg.dbus_encode(&mut data);
+    data.extend_from_slice(&msg.body);
+
+    // Feed the message in a byte at a time
+    for (i, b) in data.iter().enumerate() {
+        assert!(conn.try_read_msg().unwrap().is_none(), "message returned after {} bytes", i);
+        sock.write_all(&[*b]).unwrap();
+    }
+    let msg = conn.try_read_msg().unwrap().unwrap();
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("hello")]);
+    assert!(conn.try_read_msg().unwrap().is_none());
+
+    // A blocking read picks up where a non-blocking one left off
+    sock.write_all(&data[..10]).unwrap();
+    assert!(conn.try_read_msg().unwrap().is_none());
+    sock.write_all(&data[10..]).unwrap();
+    let msg = conn.read_msg().unwrap();
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("hello")]);
+
+    // Two messages arriving together are both returned
+    sock.write_all(&data).unwrap();
+    sock.write_all(&data).unwrap();
+    assert!(conn.try_read_msg().unwrap().is_some());
+    assert!(conn.try_read_msg().unwrap().is_some());
+    assert!(conn.try_read_msg().unwrap().is_none());
+
+    drop(sock);
+    match conn.try_read_msg() {
+        Err(Error::Disconnected) => (),
+        x => panic!("Expected Disconnected, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_observer() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize,Ordering};
+
+    struct Counter {
+        sent: Arc<AtomicUsize>,
+        received: Arc<AtomicUsize>,
+    }
+    impl MessageObserver for Counter {
+        fn on_send(&self, _: &Message) {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+        }
+        fn on_recv(&self, _: &Message) {
+            self.received.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let sent = Arc::new(AtomicUsize::new(0));
+    let received = Arc::new(AtomicUsize::new(0));
+    let (a, b) = Connection::pair().unwrap();
+    a.set_observer(Some(Box::new(Counter { sent: sent.clone(), received: received.clone() })));
+
+    let signal = message::create_signal("/com/test", "com.test", "Foo");
+    a.send(signal.clone()).unwrap();
+    a.send_all(vec![signal.clone(), signal.clone()]).unwrap();
+    for _ in 0..3 {
+        b.read_msg().unwrap();
+    }
+    b.send(signal.clone()).unwrap();
+    a.read_msg().unwrap();
+    assert_eq!(sent.load(Ordering::SeqCst), 3);
+    assert_eq!(received.load(Ordering::SeqCst), 1);
+
+    a.set_observer(None);
+    a.send(signal).unwrap();
+    assert_eq!(sent.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn test_as_raw_fd() {
+    let (client, server) = Connection::pair().unwrap();
+    let mut pfd = libc::pollfd { fd: server.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    assert!(pfd.fd >= 0);
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 0) }, 0);
+
+    client.send(message::create_signal("/com/test", "com.test", "Foo")).unwrap();
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 1000) }, 1);
+    assert!(pfd.revents & libc::POLLIN != 0);
+    server.read_msg().unwrap();
+}
+
+#[test]
+fn test_get_property() {
+    let conn = Connection::connect_session().unwrap();
+    let interfaces : Vec<String> = conn.get_property("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                                     "org.freedesktop.DBus", "Interfaces").unwrap();
+    assert!(interfaces.iter().any(|x| x == "org.freedesktop.DBus.Monitoring"));
+
+    match conn.get_property::<u32>("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                   "org.freedesktop.DBus", "Interfaces") {
+        Err(Error::BadData) => (),
+        x => panic!("Expected BadData, got {:?}", x)
+    };
+    match conn.get_property::<u32>("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                   "org.freedesktop.DBus", "Bogus") {
+        Err(Error::UnknownProperty(ref x)) if x == "Bogus" => (),
+        x => panic!("Expected UnknownProperty, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_set_property() {
+    use std::thread;
+    use dbus_serialize::types::Path;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        server.reply_error(&call, "org.freedesktop.DBus.Error.PropertyReadOnly", "read-only").unwrap();
+        let call = server.read_msg().unwrap();
+        server.send(message::create_reply(&call)).unwrap();
+        call.get_body().unwrap().unwrap()
+    });
+
+    match client.set_property("com.test", "/com/test", "com.test", "Count", 42 as u32) {
+        Err(Error::PropertyReadOnly(ref x)) if x == "Count" => (),
+        x => panic!("Expected PropertyReadOnly, got {:?}", x)
+    };
+    // A value that isn't valid D-Bus data is refused before anything is sent
+    match client.set_property("com.test", "/com/test", "com.test", "Path", Path("bad".to_owned())) {
+        Err(Error::DemarshalError(DemarshalError::InvalidObjectPath { .. })) => (),
+        x => panic!("Expected InvalidObjectPath, got {:?}", x)
+    };
+    client.set_property("com.test", "/com/test", "com.test", "Name", "foo").unwrap();
+
+    let args = handle.join().unwrap();
+    assert_eq!(args, vec![Value::from("com.test"), Value::from("Name"),
+                          Value::Variant(Variant::new(Value::from("foo"), "s"))]);
+}
+
+#[test]
+fn test_call_sync_dict() {
+    use std::thread;
+    use dbus_serialize::types::Variant;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        let mut map = HashMap::new();
+        map.insert("Count", Variant::new(Value::from(3 as u32), "u"));
+        map.insert("Name", Variant::new(Value::from("foo"), "s"));
+        server.send(message::create_reply(&call).add_arg(&map)).unwrap();
+
+        let call = server.read_msg().unwrap();
+        server.send(message::create_reply(&call).add_arg(&"not a dict")).unwrap();
+    });
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Props");
+    let map = client.call_sync_dict(msg).unwrap();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map["Count"], Value::from(3 as u32));
+    assert_eq!(map["Name"], Value::from("foo"));
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Props");
+    match client.call_sync_dict(msg) {
+        Err(Error::BadData) => (),
+        x => panic!("Expected BadData, got {:?}", x)
+    };
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_method_error() {
+    use std::thread;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        let reply = message::create_error("com.test.Error.Failed", call.serial)
+            .add_arg(&"it broke");
+        server.send(reply).unwrap();
+        let call = server.read_msg().unwrap();
+        server.send(message::create_error("com.test.Error.Quiet", call.serial)).unwrap();
+    });
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Fail");
+    let err = client.call_sync(msg).unwrap_err();
+    assert_eq!(err.to_string(), "com.test.Error.Failed: it broke");
+    match err {
+        Error::MethodError { name, message } => {
+            assert_eq!(name, "com.test.Error.Failed");
+            assert_eq!(message, Some("it broke".to_owned()));
+        },
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Fail");
+    match client.call_sync(msg) {
+        Err(Error::MethodError { name, message: None }) => assert_eq!(name, "com.test.Error.Quiet"),
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_call_sync_timeout() {
+    use std::thread;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        // Send something other than the reply, and then never answer
+        server.send(message::create_signal("/com/test", "com.test", "Busy")).unwrap();
+        let call = server.read_msg().unwrap();
+        assert_eq!(call.message_type, message::MESSAGE_TYPE_METHOD_CALL);
+        server
+    });
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Hang");
+    match client.call_sync_timeout(msg, Duration::from_millis(100)) {
+        Err(Error::Timeout) => (),
+        x => panic!("Expected Timeout, got {:?}", x)
+    };
+    let server = handle.join().unwrap();
+    // The signal is still there for read_msg
+    let sig = client.read_msg().unwrap();
+    assert_eq!(sig.message_type, message::MESSAGE_TYPE_SIGNAL);
+
+    // A reply that arrives in time is returned as usual
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        server.send(message::create_reply(&call).add_arg(&"done")).unwrap();
+    });
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Quick");
+    assert_eq!(client.call_sync_timeout(msg, Duration::from_secs(10)).unwrap().unwrap(),
+               vec![Value::from("done")]);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_call_sync_timeout_partial() {
+    use std::thread;
+
+    let (sock, mut peer) = UnixStream::pair().unwrap();
+    let client = Connection::new(Socket::Uds(sock));
+    let mut sig = message::create_signal("/com/test", "com.test", "Slow").add_arg(&"x".repeat(100));
+    sig.serial = 1;
+    let data = sig.to_bytes();
+
+    // A peer that sends a byte at a time can't hold the call past its timeout
+    let handle = thread::spawn(move || {
+        for b in data[..40].iter() {
+            peer.write_all(&[*b]).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        (peer, data)
+    });
+    let start = Instant::now();
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Hang");
+    match client.call_sync_timeout(msg, Duration::from_millis(100)) {
+        Err(Error::Timeout) => (),
+        x => panic!("Expected Timeout, got {:?}", x)
+    };
+    assert!(start.elapsed() < Duration::from_millis(300));
+
+    // The part that arrived before the timeout is kept, so the stream stays in sync
+    let (mut peer, data) = handle.join().unwrap();
+    peer.write_all(&data[40..]).unwrap();
+    let msg = client.read_msg().unwrap();
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("x".repeat(100))]);
+}
+
+#[test]
+fn test_skip_hello() {
+    let conn = ConnectionBuilder::new().skip_hello(true).connect_session().unwrap();
+    assert_eq!(conn.unique_name(), None);
+    conn.hello().unwrap();
+    assert!(conn.unique_name().unwrap().starts_with(":"));
+
+    let conn = Connection::connect_session().unwrap();
+    assert!(conn.unique_name().is_some());
+}
+
+#[test]
+fn test_list_activatable_names() {
+    let conn = Connection::connect_session().unwrap();
+    let names = conn.list_activatable_names().unwrap();
+    assert!(names.contains(&"org.freedesktop.DBus".to_owned()));
+}
+
+#[test]
+fn test_get_id() {
+    let conn = Connection::connect_session().unwrap();
+    let id = conn.get_id().unwrap();
+    assert_eq!(id.len(), 32);
+    assert!(id.chars().all(|c| c.is_digit(16)));
+    assert_eq!(Connection::connect_session().unwrap().get_id().unwrap(), id);
+}
+
+#[test]
+fn test_start_service() {
+    let conn = Connection::connect_session().unwrap();
+    // Not activatable, so the bus sends back an error rather than a start reply
+    match conn.start_service("com.test.notaservice") {
+        Err(Error::MethodError { ref name, .. }) =>
+            assert_eq!(name, "org.freedesktop.DBus.Error.ServiceUnknown"),
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_ping() {
+    let conn = Connection::connect_session().unwrap();
+    conn.ping("org.freedesktop.DBus").unwrap();
+    match conn.ping("com.test.nobody") {
+        Err(Error::MethodError { .. }) => (),
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_guid_check() {
+    let addr = env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+    let path = addr.split(',').next().unwrap();
+
+    let conn = Connection::connect(&addr).unwrap();
+    let guid = conn.server_guid().unwrap();
+    assert_eq!(guid.len(), 32);
+    Connection::connect(&(path.to_owned() + ",guid=" + &guid)).unwrap();
+
+    let bogus = path.to_owned() + ",guid=00000000000000000000000000000000";
+    match Connection::connect(&bogus) {
+        Err(Error::GuidMismatch) => (),
+        x => panic!("Expected GuidMismatch, got {:?}", x.err())
+    };
+    ConnectionBuilder::new().skip_guid_check(true).connect(&bogus).unwrap();
+}
+
+#[test]
+fn test_send_all() {
+    let conn = Connection::connect_session().unwrap();
+    let msgs = vec![
+        message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                    "org.freedesktop.DBus", "NameHasOwner")
+            .add_arg(&"org.freedesktop.DBus"),
+        message::create_method_call("org.freedesktop.DBus", "/org/freedesktop/DBus",
+                                    "org.freedesktop.DBus", "NameHasOwner")
+            .add_arg(&"com.test.nobody"),
+    ];
+    let serials = conn.send_all(msgs).unwrap();
+    assert_eq!(serials.len(), 2);
+    assert_eq!(serials[1], serials[0] + 1);
+
+    let mut replies = Vec::new();
+    while replies.len() < 2 {
+        let msg = conn.read_msg().unwrap();
+        if let Some(reply_serial) = msg.reply_serial() {
+            replies.push((reply_serial, msg.get_body().unwrap().unwrap()));
+        }
+    }
+    replies.sort_by_key(|x| x.0);
+    assert_eq!(replies[0], (serials[0], vec![Value::from(true)]));
+    assert_eq!(replies[1], (serials[1], vec![Value::from(false)]));
+}
+
+#[test]
+fn test_resend() {
+    let conn = Connection::connect_session().unwrap();
+    let msg = create_driver_call("NameHasOwner").add_arg(&"org.freedesktop.DBus");
+    let mut copy = msg.clone();
+    let first = conn.send(msg).unwrap();
+    copy.reset_serial();
+    assert_eq!(copy.serial, 0);
+    let second = conn.send(copy.clone()).unwrap();
+    assert!(second != first);
+
+    // The retransmitted copy still works as a method call
+    let resp = conn.call_sync(copy).unwrap().unwrap();
+    assert_eq!(resp, vec![Value::from(true)]);
+}
+
+#[test]
+fn test_tcp_options() {
+    let addr = env::var("DBUS_TCP_BUS_ADDRESS").unwrap();
+    let port = addr.split(|c| c == ':' || c == ',')
+        .find(|x| x.starts_with("port="))
+        .map(|x| x[5..].to_owned())
+        .unwrap();
+
+    let conn = ConnectionBuilder::new().tcp_nodelay(true).tcp_keepalive(true)
+        .connect_tcp(("127.0.0.1", port.parse::<u16>().unwrap())).unwrap();
+    assert_eq!(conn.transport(), Transport::Tcp);
+    match *conn.sock.borrow() {
+        Socket::Tcp(ref x) => assert!(x.nodelay().unwrap()),
+        _ => panic!("Expected a TCP socket")
+    };
+    conn.set_tcp_nodelay(false).unwrap();
+    conn.set_keepalive(false).unwrap();
+    match *conn.sock.borrow() {
+        Socket::Tcp(ref x) => assert!(!x.nodelay().unwrap()),
+        _ => panic!("Expected a TCP socket")
+    };
+
+    let conn = Connection::connect_session().unwrap();
+    assert_eq!(conn.transport(), Transport::Unix);
+    match conn.set_tcp_nodelay(true) {
+        Err(Error::NotTcp) => (),
+        x => panic!("Expected NotTcp, got {:?}", x)
+    };
+    match conn.set_keepalive(true) {
+        Err(Error::NotTcp) => (),
+        x => panic!("Expected NotTcp, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_tcp_bind_addr() {
+    let addr = env::var("DBUS_TCP_BUS_ADDRESS").unwrap();
+    let port = addr.split(|c| c == ':' || c == ',')
+        .find(|x| x.starts_with("port="))
+        .map(|x| x[5..].parse::<u16>().unwrap())
+        .unwrap();
+
+    let local = "127.0.0.1:0".parse().unwrap();
+    let conn = ConnectionBuilder::new().tcp_bind_addr(local)
+        .connect_tcp(("127.0.0.1", port)).unwrap();
+    match *conn.sock.borrow() {
+        Socket::Tcp(ref x) => {
+            let bound = x.local_addr().unwrap();
+            assert_eq!(bound.ip(), local.ip());
+            assert!(bound.port() != 0);
+        },
+        _ => panic!("Expected a TCP socket")
+    };
+    assert!(conn.unique_name().is_some());
+
+    // Binding to an address we don't have fails
+    let local = "192.0.2.1:0".parse().unwrap();
+    assert!(ConnectionBuilder::new().tcp_bind_addr(local).connect_tcp(("127.0.0.1", port)).is_err());
+}
+
+#[test]
+fn test_from_stream() {
+    // Something that's Read + Write, but not one of the socket types Connection knows about
+    struct Wrapper(UnixStream);
+    impl Read for Wrapper {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+    impl Write for Wrapper {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    let addr = env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+    let sock = match ServerAddress::from_str(&addr).unwrap() {
+        ServerAddress::Unix(x) => UnixStream::connect(x.path()).unwrap(),
+        x => panic!("Expected a unix address, got {:?}", x)
+    };
+    let conn = Connection::from_stream(Wrapper(sock)).unwrap();
+    assert_eq!(conn.transport(), Transport::Other);
+    assert!(conn.unique_name().is_some());
+    conn.ping("org.freedesktop.DBus").unwrap();
+    assert!(conn.wait_for_signal("com.test", "Foo", Some(Duration::from_millis(10))).is_err());
+}
+
+#[test]
+fn test_nonce_tcp() {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let noncefile = env::temp_dir().join(format!("dbus-bytestream-nonce-{}", process::id()));
+    let addr = format!("nonce-tcp:host=127.0.0.1,port={},noncefile={}", port,
+                       noncefile.to_str().unwrap());
+
+    match Connection::connect(&addr) {
+        Err(Error::AuthFailed) => (),
+        x => panic!("Expected AuthFailed for a missing nonce file, got {:?}", x.err())
+    };
+
+    let nonce : Vec<u8> = (0..16).collect();
+    File::create(&noncefile).unwrap().write_all(&nonce).unwrap();
+    let handle = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut buf = vec![0; 17];
+        sock.read_exact(&mut buf).unwrap();
+        buf
+    });
+    // The server hangs up without authenticating, so the connection itself fails
+    assert!(Connection::connect(&addr).is_err());
+    fs::remove_file(&noncefile).unwrap();
+
+    let buf = handle.join().unwrap();
+    assert_eq!(&buf[..16], &nonce[..]);
+    assert_eq!(buf[16], 0);
+}
+
 #[test]
 fn test_tcp() {
     let conn = Connection::connect(&env::var("DBUS_TCP_BUS_ADDRESS").unwrap()).unwrap();
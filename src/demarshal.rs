@@ -4,41 +4,55 @@ use std::mem::transmute;
 
 use dbus_serialize::types::{Value,BasicValue,Path,Signature,Struct,Variant,Array,Dictionary};
 
+use message::Endianness;
+
+/// Errors that can occur while demarshaling.  Where it's meaningful, the offset of the data that
+/// caused the error is included.
 #[derive(Debug, Clone)]
 pub enum DemarshalError {
-    MessageTooShort,
-    CorruptedMessage,
-    BadUTF8,
+    MessageTooShort { offset: usize },
+    CorruptedMessage { offset: usize },
+    BadUTF8 { offset: usize },
     BadSignature,
-    ElementTooBig,
+    ElementTooBig { offset: usize },
     MismatchedParens,
+    /// An object path holds a character that isn't allowed where it is
+    InvalidObjectPath { offset: usize },
+    /// A signature holds a character that isn't a type code
+    InvalidSignature { offset: usize },
 }
 
 impl fmt::Display for DemarshalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let msg = match *self {
-            DemarshalError::MessageTooShort  => "message too short",
-            DemarshalError::CorruptedMessage => "corrupted message",
-            DemarshalError::BadUTF8          => "bad utf-8",
-            DemarshalError::BadSignature     => "bad signature",
-            DemarshalError::ElementTooBig    => "element too big",
-            DemarshalError::MismatchedParens => "mismatched parens",
-        };
-
-        write!(f, "{}", msg)
+        match *self {
+            DemarshalError::MessageTooShort { offset }  => write!(f, "message too short at byte {}", offset),
+            DemarshalError::CorruptedMessage { offset } => write!(f, "corrupted message at byte {}", offset),
+            DemarshalError::BadUTF8 { offset }          => write!(f, "bad utf-8 at byte {}", offset),
+            DemarshalError::BadSignature                => write!(f, "bad signature"),
+            DemarshalError::ElementTooBig { offset }    => write!(f, "element too big at byte {}", offset),
+            DemarshalError::MismatchedParens            => write!(f, "mismatched parens"),
+            DemarshalError::InvalidObjectPath { offset } => write!(f, "invalid object path at byte {}", offset),
+            DemarshalError::InvalidSignature { offset } => write!(f, "invalid signature at byte {}", offset),
+        }
     }
 }
 
+/// Returns the alignment of the given D-Bus type code.
+///
+/// # Panics
+/// Panics if sig is not a valid type code.
 pub fn get_alignment(sig: char) -> usize {
     match sig {
         'y' => 1,
-        'b' => 1,
+        'b' => 4,
         'n' => 2,
         'q' => 2,
         'i' => 4,
         'u' => 4,
+        'h' => 4,
         'x' => 8,
         't' => 8,
+        'd' => 8,
         's' => 4,
         'o' => 4,
         'g' => 1,
@@ -51,12 +65,61 @@ pub fn get_alignment(sig: char) -> usize {
     }
 }
 
-fn demarshal_byte(buf: &mut Vec<u8>, offset: &mut usize) -> Result<Value,DemarshalError> {
-    if buf.len() < 1 {
-        return Err(DemarshalError::MessageTooShort);
+fn is_basic_type(sig: char) -> bool {
+    "ybnqiuhxtdsog".contains(sig)
+}
+
+/// Returns the length of the single complete type at the start of sig, or BadSignature if sig
+/// doesn't start with one.  This lets us check a signature without having any data for it, as
+/// happens with empty arrays.
+pub(crate) fn single_type_len(sig: &str) -> Result<usize,DemarshalError> {
+    let typ = match sig.chars().next() {
+        Some(x) => x,
+        None => return Err(DemarshalError::BadSignature)
+    };
+    match typ {
+        'a' => Ok(1 + try!(single_type_len(&sig[1..]))),
+        '(' => {
+            let mut len = 1;
+            while !sig[len..].starts_with(')') {
+                len += try!(single_type_len(&sig[len..]));
+            }
+            if len == 1 {
+                // Empty structs aren't allowed
+                return Err(DemarshalError::BadSignature);
+            }
+            Ok(len + 1)
+        },
+        '{' => {
+            // Dict entries have exactly two members, and the key must be a basic type
+            match sig.chars().nth(1) {
+                Some(x) if is_basic_type(x) => (),
+                _ => return Err(DemarshalError::BadSignature)
+            };
+            let len = 2 + try!(single_type_len(&sig[2..]));
+            if !sig[len..].starts_with('}') {
+                return Err(DemarshalError::BadSignature);
+            }
+            Ok(len + 1)
+        },
+        'v' => Ok(1),
+        x if is_basic_type(x) => Ok(1),
+        _ => Err(DemarshalError::BadSignature)
+    }
+}
+
+/// Returns the next len bytes of buf, starting at offset, and moves offset past them.
+fn take_bytes<'a>(buf: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8],DemarshalError> {
+    if buf.len() < *offset || buf.len() - *offset < len {
+        return Err(DemarshalError::MessageTooShort { offset: *offset });
     }
-    let byte = buf.remove(0);
-    *offset += 1;
+    let bytes = &buf[*offset..*offset + len];
+    *offset += len;
+    Ok(bytes)
+}
+
+fn demarshal_byte(buf: &mut Vec<u8>, offset: &mut usize) -> Result<Value,DemarshalError> {
+    let byte = try!(take_bytes(buf, offset, 1))[0];
     Ok(Value::BasicValue(BasicValue::Byte(byte)))
 }
 
@@ -65,47 +128,26 @@ fn align_to(buf: &mut Vec<u8>, offset: &mut usize, align: usize) -> Result<(),De
         return Ok(());
     }
     let delta = align - (*offset % align);
-    if buf.len() < delta {
-        return Err(DemarshalError::MessageTooShort);
-    }
-    for _ in 0..delta {
-        buf.remove(0);
-        *offset += 1;
-    }
+    try!(take_bytes(buf, offset, delta));
     Ok(())
 }
 
-fn demarshal_bool(buf: &mut Vec<u8>, offset: &mut usize) -> Result<Value,DemarshalError> {
+fn demarshal_bool(buf: &mut Vec<u8>, offset: &mut usize, order: Endianness) -> Result<Value,DemarshalError> {
     try!(align_to(buf, offset, 4));
-    if buf.len() < 4 {
-        return Err(DemarshalError::MessageTooShort);
-    }
-    let byte = buf.remove(0);
-    *offset += 1;
-    // XXX: assumes LE
-    for _ in 0..3 {
-        *offset += 1;
-        // Only the first byte should have a non-zero value
-        if buf.remove(0) != 0 {
-            return Err(DemarshalError::CorruptedMessage);
-        }
-    }
-    match byte {
-        0 => Ok(Value::BasicValue(BasicValue::Boolean(false))),
-        1 => Ok(Value::BasicValue(BasicValue::Boolean(true))),
-        _ => Err(DemarshalError::CorruptedMessage)
+    let start = *offset;
+    match try!(demarshal_int(buf, offset, 4, false, order)) {
+        Value::BasicValue(BasicValue::Uint32(0)) => Ok(Value::BasicValue(BasicValue::Boolean(false))),
+        Value::BasicValue(BasicValue::Uint32(1)) => Ok(Value::BasicValue(BasicValue::Boolean(true))),
+        _ => Err(DemarshalError::CorruptedMessage { offset: start })
     }
 }
 
-fn demarshal_int(buf: &mut Vec<u8>, offset: &mut usize, len: usize, is_signed: bool) -> Result<Value,DemarshalError> {
+fn demarshal_int(buf: &mut Vec<u8>, offset: &mut usize, len: usize, is_signed: bool, order: Endianness) -> Result<Value,DemarshalError> {
     try!(align_to(buf, offset, len));
-    if buf.len() < len {
-        return Err(DemarshalError::MessageTooShort);
-    }
     let mut intbuf = [0; 8];
-    for i in 0..len {
-        intbuf[i] = buf.remove(0);
-        *offset += 1;
+    intbuf[..len].copy_from_slice(try!(take_bytes(buf, offset, len)));
+    if order == Endianness::Big {
+        intbuf[..len].reverse();
     }
     // Check for sign-extension
     if is_signed && (intbuf[len-1] & 128 == 128) {
@@ -132,24 +174,65 @@ fn demarshal_int(buf: &mut Vec<u8>, offset: &mut usize, len: usize, is_signed: b
     }
 }
 
-fn demarshal_string(buf: &mut Vec<u8>, offset: &mut usize, count_size: usize, is_path: bool) -> Result<Value,DemarshalError> {
+fn demarshal_double(buf: &mut Vec<u8>, offset: &mut usize, order: Endianness) -> Result<Value,DemarshalError> {
+    // demarshal_int takes care of alignment and byte order for us
+    let start = *offset;
+    match try!(demarshal_int(buf, offset, 8, false, order)) {
+        Value::BasicValue(BasicValue::Uint64(x)) => Ok(Value::Double(f64::from_bits(x))),
+        _ => Err(DemarshalError::CorruptedMessage { offset: start }),
+    }
+}
+
+/// Returns the index of the first character of path that makes it an invalid object path, or None
+/// if it's valid.  A path is a '/' followed by elements of [A-Za-z0-9_] separated by single
+/// slashes, and only the root path "/" may end with a slash.
+pub(crate) fn invalid_path_offset(path: &str) -> Option<usize> {
+    let bytes = path.as_bytes();
+    if bytes.first() != Some(&b'/') {
+        return Some(0);
+    }
+    for (i, &c) in bytes.iter().enumerate().skip(1) {
+        if c == b'/' {
+            if bytes[i - 1] == b'/' {
+                return Some(i);
+            }
+        } else if !(c.is_ascii_alphanumeric() || c == b'_') {
+            return Some(i);
+        }
+    }
+    if bytes.len() > 1 && bytes[bytes.len() - 1] == b'/' {
+        return Some(bytes.len() - 1);
+    }
+    None
+}
+
+fn demarshal_string(buf: &mut Vec<u8>, offset: &mut usize, count_size: usize, is_path: bool, order: Endianness) -> Result<Value,DemarshalError> {
     // demarshal_int ensure we're correctly aligned with input
-    let len = match demarshal_int(buf, offset, count_size, false) {
-        Ok(Value::BasicValue(BasicValue::Uint32(x))) => x,
-        Ok(Value::BasicValue(BasicValue::Byte(x))) => x as u32,
-        _ => return Err(DemarshalError::CorruptedMessage),
+    let len = match try!(demarshal_int(buf, offset, count_size, false, order)) {
+        Value::BasicValue(BasicValue::Uint32(x)) => x,
+        Value::BasicValue(BasicValue::Byte(x)) => x as u32,
+        _ => return Err(DemarshalError::CorruptedMessage { offset: *offset }),
     };
-    let mut strbuf = Vec::new();
-    for _ in 0..len {
-        strbuf.push(buf.remove(0));
-        *offset += 1
-    }
+    // The string is followed by a NUL byte
+    let bytes = try!(take_bytes(buf, offset, (len as usize) + 1));
     // Check the NUL byte
-    if buf.remove(0) != 0 {
-        return Err(DemarshalError::CorruptedMessage);
+    if bytes[len as usize] != 0 {
+        return Err(DemarshalError::CorruptedMessage { offset: *offset - 1 });
+    }
+    let start = *offset - (len as usize) - 1;
+    let val = match String::from_utf8(bytes[..len as usize].to_vec()) {
+        Ok(x) => x,
+        Err(e) => return Err(DemarshalError::BadUTF8 { offset: start + e.utf8_error().valid_up_to() })
+    };
+    if is_path {
+        if let Some(x) = invalid_path_offset(&val) {
+            return Err(DemarshalError::InvalidObjectPath { offset: start + x });
+        }
+    } else if count_size == 1 {
+        if let Some(x) = val.find(|c| !"ybnqiuhxtdsogav(){}".contains(c)) {
+            return Err(DemarshalError::InvalidSignature { offset: start + x });
+        }
     }
-    *offset += 1;
-    let val = try!(String::from_utf8(strbuf).or(Err(DemarshalError::BadUTF8)));
     if is_path {
         Ok(Value::BasicValue(BasicValue::ObjectPath(Path(val))))
     } else {
@@ -161,52 +244,106 @@ fn demarshal_string(buf: &mut Vec<u8>, offset: &mut usize, count_size: usize, is
     }
 }
 
-fn demarshal_array(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) -> Result<Value,DemarshalError> {
-    if sig.len() < 1 {
-        return Err(DemarshalError::BadSignature);
-    }
-    let typ = sig.chars().next().unwrap();
-    let is_dict = typ == '{';
+/// Demarshals the elements of an array, whose signature (after the 'a') is at the start of sig,
+/// handing each one to f.  Returns the element signature.
+fn demarshal_elements<F>(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, order: Endianness, mut f: F) -> Result<String,DemarshalError>
+    where F: FnMut(Value) {
+    // Work out the element type up front, since there may not be any elements to demarshal
+    let elem_len = try!(single_type_len(sig));
+    let elem_sig : String = sig.drain(..elem_len).collect();
+    let typ = elem_sig.chars().next().unwrap();
     // demarshal_int ensure we're correctly aligned with input
-    let array_len = match demarshal_int(buf, offset, 4, false) {
-        Ok(Value::BasicValue(BasicValue::Uint32(x))) => x,
-        _ => return Err(DemarshalError::CorruptedMessage),
+    let array_len = match try!(demarshal_int(buf, offset, 4, false, order)) {
+        Value::BasicValue(BasicValue::Uint32(x)) => x,
+        _ => return Err(DemarshalError::CorruptedMessage { offset: *offset }),
     };
     if array_len > 1 << 26 {
-        return Err(DemarshalError::ElementTooBig);
+        return Err(DemarshalError::ElementTooBig { offset: *offset - 4 });
     }
     try!(align_to(buf, offset, get_alignment(typ)));
-    if buf.len() < (array_len as usize) {
-        return Err(DemarshalError::MessageTooShort);
+    if buf.len() - *offset < (array_len as usize) {
+        return Err(DemarshalError::MessageTooShort { offset: *offset });
     }
 
-    let mut vec = Vec::new();
     let start_offset = *offset;
-    let mut sig_copy = "".to_owned();
     while *offset < start_offset+(array_len as usize) {
         // We want to pass the same signature to each call of demarshal
-        sig_copy = sig.to_owned();
-        vec.push(try!(demarshal(buf, offset, &mut sig_copy)));
+        let mut sig_copy = elem_sig.clone();
+        if typ == '{' {
+            // Dict entries are only allowed here, so demarshal won't take them
+            sig_copy.remove(0);
+            f(try!(demarshal_struct(buf, offset, &mut sig_copy, '{', order)));
+        } else {
+            f(try!(demarshal_with_order(buf, offset, &mut sig_copy, order)));
+        }
+    }
+    Ok(elem_sig)
+}
+
+/// Demarshals an array, passing each element to f as soon as it's decoded rather than collecting
+/// them.  sig starts with the array's signature, as for demarshal.  Only one decoded element
+/// exists at a time, so the memory used on top of buf is bounded by the largest element instead
+/// of growing with the length of the array.  Dictionary entries are passed as two-member Structs.
+/// The data is taken to be little-endian.
+pub fn demarshal_array_each<F>(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, f: F) -> Result<(),DemarshalError>
+    where F: FnMut(Value) {
+    demarshal_array_each_with_order(buf, offset, sig, Endianness::Little, f)
+}
+
+/// Like demarshal_array_each, but for data in the given byte order.
+pub fn demarshal_array_each_with_order<F>(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, order: Endianness, f: F) -> Result<(),DemarshalError>
+    where F: FnMut(Value) {
+    if !sig.starts_with('a') {
+        return Err(DemarshalError::BadSignature);
+    }
+    sig.remove(0);
+    try!(demarshal_elements(buf, offset, sig, order, f));
+    Ok(())
+}
+
+/// Splits a demarshaled dict entry into its key and value
+fn split_dict_entry(x: Value) -> (BasicValue, Value) {
+    let mut s = match x {
+        Value::Struct(x) => x,
+        _ => panic!("Dictionaries should contain structs")
+    };
+    let val = s.objects.remove(1);
+    let key = match s.objects.remove(0) {
+        Value::BasicValue(x) => x,
+        _ => panic!("Dictionaries require BasicValue keys")
+    };
+    (key, val)
+}
+
+/// Demarshals a dictionary, keeping the entries in the order they appear in buf instead of
+/// collecting them into the HashMap of a Value::Dictionary.  sig starts with the dictionary's
+/// signature, as for demarshal.  Duplicate keys are all kept.  The data is taken to be
+/// little-endian.
+pub fn demarshal_dict_ordered(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) -> Result<Vec<(BasicValue, Value)>,DemarshalError> {
+    demarshal_dict_ordered_with_order(buf, offset, sig, Endianness::Little)
+}
+
+/// Like demarshal_dict_ordered, but for data in the given byte order.
+pub fn demarshal_dict_ordered_with_order(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, order: Endianness) -> Result<Vec<(BasicValue, Value)>,DemarshalError> {
+    if !sig.starts_with("a{") {
+        return Err(DemarshalError::BadSignature);
     }
-    // Now that we're done with our elements we can forget the elements consumed by demarshal
-    let mut mysig = sig.clone();
-    mysig.truncate(sig.len() - sig_copy.len());
-    mysig.insert(0, 'a');
-    *sig = sig_copy;
+    let mut entries = Vec::new();
+    try!(demarshal_array_each_with_order(buf, offset, sig, order, |x| entries.push(split_dict_entry(x))));
+    Ok(entries)
+}
+
+fn demarshal_array(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, order: Endianness) -> Result<Value,DemarshalError> {
+    let mut vec = Vec::new();
+    let elem_sig = try!(demarshal_elements(buf, offset, sig, order, |x| vec.push(x)));
+    let is_dict = elem_sig.starts_with('{');
+    let mysig = "a".to_owned() + &elem_sig;
 
     if is_dict {
         let mut map : HashMap<BasicValue,Value> = HashMap::new();
         for x in vec {
-            let mut s = match x {
-                Value::Struct(x) => x,
-                _ => panic!("Dictionaries should contain structs")
-            };
-            let val = s.objects.remove(1);
-            let key = match s.objects[0] {
-                Value::BasicValue(ref x) => x,
-                _ => panic!("Dictionaries require BasicValue keys")
-            };
-            map.insert(key.clone(), val);
+            let (key, val) = split_dict_entry(x);
+            map.insert(key, val);
         }
         return Ok(Value::Dictionary(Dictionary::new_with_sig(map, mysig)));
     }
@@ -214,11 +351,14 @@ fn demarshal_array(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) -> R
     Ok(Value::Array(Array::new_with_sig(vec, mysig)))
 }
 
-fn demarshal_struct(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) -> Result<Value,DemarshalError> {
+fn demarshal_struct(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, open: char, order: Endianness) -> Result<Value,DemarshalError> {
     if sig.len() < 1 {
         return Err(DemarshalError::BadSignature);
     }
+    // Dict entries are encoded just like structs, but are delimited by braces
+    let close = if open == '{' { '}' } else { ')' };
     try!(align_to(buf, offset, 8));
+    let start = *offset;
 
     let mut vec = Vec::new();
     let mut mysig = sig.to_owned();
@@ -227,16 +367,21 @@ fn demarshal_struct(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) ->
             Some(x) => x,
             None => return Err(DemarshalError::MismatchedParens)
         };
-        if typ == ')' {
+        if typ == close {
             sig.remove(0);
             break;
         }
-        vec.push(try!(demarshal(buf, offset, sig)));
+        vec.push(try!(demarshal_with_order(buf, offset, sig, order)));
     }
     // Only keep the characters that were consumed by demarshal
     let oldlen = mysig.len();
     mysig.truncate(oldlen - sig.len());
-    mysig.insert(0, '(');
+    mysig.insert(0, open);
+    // The members we read should add up to exactly one complete type
+    match single_type_len(&mysig) {
+        Ok(x) if x == mysig.len() => (),
+        _ => return Err(DemarshalError::CorruptedMessage { offset: start })
+    };
 
     Ok(Value::Struct(Struct{
         objects: vec,
@@ -244,49 +389,117 @@ fn demarshal_struct(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) ->
     }))
 }
 
-fn demarshal_variant(buf: &mut Vec<u8>, offset: &mut usize) -> Result<Value,DemarshalError> {
+fn demarshal_variant(buf: &mut Vec<u8>, offset: &mut usize, order: Endianness) -> Result<Value,DemarshalError> {
     let mut variant_sig = "g".to_owned();
-    let sigval = try!(demarshal(buf, offset, &mut variant_sig));
+    let sigval = try!(demarshal_with_order(buf, offset, &mut variant_sig, order));
     let sig = match sigval {
         Value::BasicValue(BasicValue::Signature(x)) => x,
-        _ => return Err(DemarshalError::CorruptedMessage)
+        _ => return Err(DemarshalError::CorruptedMessage { offset: *offset })
     };
     let mut s = sig.0.to_owned();
-    let var = try!(demarshal(buf, offset, &mut s));
+    let var = try!(demarshal_with_order(buf, offset, &mut s, order));
+    // A variant holds exactly one complete type
+    if !s.is_empty() {
+        return Err(DemarshalError::BadSignature);
+    }
     Ok(Value::Variant(Variant{
         object: Box::new(var),
         signature: sig
     }))
 }
 
+/// Demarshals the first complete type in sig from buf, starting at offset, and removes it from
+/// sig.  offset is moved past the data that was read; buf itself is left untouched.  The data is
+/// taken to be little-endian.
 pub fn demarshal(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String) -> Result<Value,DemarshalError> {
+    demarshal_with_order(buf, offset, sig, Endianness::Little)
+}
+
+/// Like demarshal, but for data in the given byte order, as set by the endianness flag of the
+/// message it came from.
+pub fn demarshal_with_order(buf: &mut Vec<u8>, offset: &mut usize, sig: &mut String, order: Endianness) -> Result<Value,DemarshalError> {
+    if sig.is_empty() {
+        return Err(DemarshalError::BadSignature);
+    }
     let typ = sig.remove(0);
     match typ {
         'y' => demarshal_byte(buf, offset),
-        'b' => demarshal_bool(buf, offset),
-        'n' => demarshal_int(buf, offset, 2, true),
-        'q' => demarshal_int(buf, offset, 2, false),
-        'i' => demarshal_int(buf, offset, 4, true),
-        'u' => demarshal_int(buf, offset, 4, false),
-        'x' => demarshal_int(buf, offset, 8, true),
-        't' => demarshal_int(buf, offset, 8, false),
-        's' => demarshal_string(buf, offset, 4, false),
-        'o' => demarshal_string(buf, offset, 4, true),
-        'g' => demarshal_string(buf, offset, 1, false),
-
-        'a' => demarshal_array(buf, offset, sig),
-        '(' => demarshal_struct(buf, offset, sig),
-        '{' => demarshal_struct(buf, offset, sig),
-        'v' => demarshal_variant(buf, offset),
+        'b' => demarshal_bool(buf, offset, order),
+        'n' => demarshal_int(buf, offset, 2, true, order),
+        'q' => demarshal_int(buf, offset, 2, false, order),
+        'i' => demarshal_int(buf, offset, 4, true, order),
+        'u' => demarshal_int(buf, offset, 4, false, order),
+        // File descriptors are sent out of band, so all that's here is the index of one among the
+        // message's descriptors
+        'h' => demarshal_int(buf, offset, 4, false, order),
+        'x' => demarshal_int(buf, offset, 8, true, order),
+        't' => demarshal_int(buf, offset, 8, false, order),
+        'd' => demarshal_double(buf, offset, order),
+        's' => demarshal_string(buf, offset, 4, false, order),
+        'o' => demarshal_string(buf, offset, 4, true, order),
+        'g' => demarshal_string(buf, offset, 1, false, order),
+
+        'a' => demarshal_array(buf, offset, sig, order),
+        '(' => demarshal_struct(buf, offset, sig, '(', order),
+        // A dict entry outside of an array
+        '{' => Err(DemarshalError::BadSignature),
+        'v' => demarshal_variant(buf, offset, order),
         _ => Err(DemarshalError::BadSignature)
     }
 }
 
+/// Demarshals a sequence of values stored outside of any message, such as a message body saved
+/// on its own.  bytes must start at an 8-byte boundary of the original data, and sig may hold any
+/// number of complete types.  Returns one Value for each of them.
+pub fn decode_standalone(sig: &str, bytes: &[u8]) -> Result<Vec<Value>,DemarshalError> {
+    let mut buf = bytes.to_vec();
+    let mut sig = sig.to_owned();
+    let mut offset = 0;
+    let mut values = Vec::new();
+    while !sig.is_empty() {
+        values.push(try!(demarshal(&mut buf, &mut offset, &mut sig)));
+    }
+    Ok(values)
+}
+
+/// Returns the total length on the wire of the message at the start of buf, including its body,
+/// by looking only at the fixed header and the length of the header field array.  Returns
+/// MessageTooShort if buf doesn't yet hold the whole message.
+pub fn framed_message_len(buf: &[u8]) -> Result<usize,DemarshalError> {
+    if buf.len() < 16 {
+        return Err(DemarshalError::MessageTooShort { offset: buf.len() });
+    }
+    let read_u32 = |start: usize| {
+        let mut x = 0;
+        for i in 0..4 {
+            let byte = if buf[0] == b'B' { buf[start + i] } else { buf[start + 3 - i] };
+            x = (x << 8) | byte as usize;
+        }
+        x
+    };
+    if buf[0] != b'l' && buf[0] != b'B' {
+        return Err(DemarshalError::CorruptedMessage { offset: 0 });
+    }
+    let body_len = read_u32(4);
+    let fields_len = read_u32(12);
+    let mut header_len = 16 + fields_len;
+    header_len += (8 - header_len % 8) % 8;
+    let len = header_len + body_len;
+    if buf.len() < len {
+        return Err(DemarshalError::MessageTooShort { offset: buf.len() });
+    }
+    Ok(len)
+}
+
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
     use marshal::Marshal;
-    use demarshal::demarshal;
-    use dbus_serialize::types::{Value,BasicValue,Signature};
+    use demarshal::{demarshal,demarshal_with_order,demarshal_array_each,demarshal_array_each_with_order,demarshal_dict_ordered,demarshal_dict_ordered_with_order,decode_standalone,framed_message_len,get_alignment,DemarshalError};
+    use dbus_serialize::types::{Value,BasicValue,Path,Signature,Struct,Array,Variant};
+    use message;
+    use message::Endianness;
 
     #[test]
     fn test_demarshal_u32() {
@@ -298,7 +511,7 @@ mod test {
         let mut offset = 0;
         let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
         assert_eq!(v, Value::BasicValue(BasicValue::Uint32(16)));
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
     }
 
@@ -310,14 +523,27 @@ mod test {
         let mut sig = x.get_type();
         x.dbus_encode(&mut buf);
 
-        buf.remove(0);
         let mut offset = 1;
         let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
         assert_eq!(v, Value::BasicValue(BasicValue::Uint32(16)));
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
     }
 
+    #[test]
+    fn test_demarshal_big_endian() {
+        let mut buf = vec![0, 0, 0, 16, 0, 1, 0, 0, 0, 0, 0, 1];
+        let mut offset = 0;
+        let mut sig = "uqb".to_owned();
+        let v = demarshal_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big).unwrap();
+        assert_eq!(v, Value::from(16 as u32));
+        let v = demarshal_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big).unwrap();
+        assert_eq!(v, Value::from(1 as u16));
+        let v = demarshal_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big).unwrap();
+        assert_eq!(v, Value::from(true));
+        assert_eq!(offset, buf.len());
+    }
+
     #[test]
     fn test_string() {
         let mut buf = Vec::new();
@@ -328,10 +554,33 @@ mod test {
         let mut offset = 0;
         let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
         assert_eq!(v, Value::BasicValue(BasicValue::String("swalter".to_string())));
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
     }
 
+    #[test]
+    fn test_empty_strings() {
+        let values = vec![
+            Value::BasicValue(BasicValue::String("".to_owned())),
+            // An empty object path isn't valid, so use the shortest one that is
+            Value::BasicValue(BasicValue::ObjectPath(Path("/".to_owned()))),
+            Value::BasicValue(BasicValue::Signature(Signature("".to_owned()))),
+        ];
+        for v in values {
+            // A trailing byte makes sure the NUL is consumed and nothing more
+            let mut buf = Vec::new();
+            let mut sig = v.get_type();
+            v.dbus_encode(&mut buf);
+            buf.push(7);
+
+            let mut offset = 0;
+            let x = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+            assert_eq!(x, v);
+            assert_eq!(offset, buf.len() - 1);
+            assert_eq!(sig, "");
+        }
+    }
+
     #[test]
     fn test_array() {
         let mut buf = Vec::new();
@@ -351,7 +600,7 @@ mod test {
             Value::BasicValue(BasicValue::Uint32(3)),
         ];
         assert_eq!(arr.objects, golden);
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
     }
 
@@ -374,7 +623,7 @@ mod test {
             Value::BasicValue(BasicValue::Byte(3)),
         ];
         assert_eq!(arr.objects, golden);
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
     }
 
@@ -388,7 +637,7 @@ mod test {
 
         let mut offset = 0;
         let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
-        assert_eq!(buf.len(), 0);
+        assert_eq!(offset, buf.len());
         assert_eq!(sig, "");
         let s = match v {
             Value::Struct(x) => x,
@@ -396,4 +645,452 @@ mod test {
         };
         assert_eq!(s.signature, Signature("(ss)".to_string()));
     }
+
+    #[test]
+    fn test_struct_short() {
+        // Only one of the two strings is present
+        let mut buf = Vec::new();
+        "swalter".dbus_encode(&mut buf);
+        let mut offset = 0;
+        let mut sig = "(ss)".to_string();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::MessageTooShort { offset }) => assert_eq!(offset, 12),
+            x => panic!("Expected MessageTooShort, got {:?}", x)
+        };
+
+        // A struct with no members at all
+        let mut buf = vec![0; 8];
+        let mut offset = 0;
+        let mut sig = "()".to_string();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::CorruptedMessage { offset }) => assert_eq!(offset, 0),
+            x => panic!("Expected CorruptedMessage, got {:?}", x)
+        };
+    }
+
+    #[test]
+    fn test_dict() {
+        let mut buf = Vec::new();
+        let mut map = HashMap::new();
+        map.insert("swalter", 42 as u32);
+        let mut sig = map.get_type();
+        map.dbus_encode(&mut buf);
+
+        let mut offset = 0;
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(offset, buf.len());
+        assert_eq!(sig, "");
+        let d = match v {
+            Value::Dictionary(x) => x,
+            _ => panic!("Bad return from demarshal {:?}", v)
+        };
+        assert_eq!(d.map.get(&BasicValue::String("swalter".to_string())),
+                   Some(&Value::BasicValue(BasicValue::Uint32(42))));
+    }
+
+    #[test]
+    fn test_error_offset() {
+        // A string claiming to be longer than the data that follows it
+        let mut buf = vec![0, 0, 0, 0, 100, 0, 0, 0, 'a' as u8];
+        let mut offset = 4;
+        let mut sig = "s".to_string();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::MessageTooShort { offset }) => assert_eq!(offset, 8),
+            x => panic!("Expected MessageTooShort, got {:?}", x)
+        };
+
+        // A boolean that isn't 0 or 1
+        let mut buf = vec![1, 0, 0, 0, 2, 0, 0, 0];
+        let mut offset = 0;
+        let mut sig = "bb".to_string();
+        demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(e @ DemarshalError::CorruptedMessage { .. }) => {
+                assert_eq!(e.to_string(), "corrupted message at byte 4");
+            },
+            x => panic!("Expected CorruptedMessage, got {:?}", x)
+        };
+    }
+
+    #[test]
+    fn test_double() {
+        let mut buf = Vec::new();
+        let x = 2.5 as f64;
+        let mut sig = x.get_type();
+        x.dbus_encode(&mut buf);
+
+        let mut offset = 0;
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v, Value::Double(2.5));
+        assert_eq!(offset, 8);
+        assert_eq!(sig, "");
+    }
+
+    #[test]
+    fn test_array_doubles() {
+        let mut buf = Vec::new();
+        let x = vec![1.5 as f64, -2.25 as f64];
+        let mut sig = x.get_type();
+        x.dbus_encode(&mut buf);
+        // Length, then padding up to the 8-byte aligned elements
+        assert_eq!(&buf[0..8], &[16, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut offset = 0;
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        let arr = match v {
+            Value::Array(x) => x,
+            _ => panic!("Bad return from demarshal {:?}", v)
+        };
+        assert_eq!(arr.objects, vec![Value::Double(1.5), Value::Double(-2.25)]);
+        assert_eq!(offset, buf.len());
+        assert_eq!(sig, "");
+    }
+
+    #[test]
+    fn test_struct_double() {
+        let mut buf = Vec::new();
+        let x = Struct {
+            objects: vec![Value::from("swalter"), Value::Double(3.5)],
+            signature: Signature("(sd)".to_string())
+        };
+        let mut sig = x.get_type();
+        // Start unaligned so the struct itself has to be padded too
+        buf.push(0);
+        x.dbus_encode(&mut buf);
+        assert_eq!(buf.len(), 8 + 12 + 4 + 8);
+
+        let mut offset = 1;
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v, Value::Struct(x));
+        assert_eq!(offset, buf.len());
+        assert_eq!(sig, "");
+
+        // An array of such structs
+        let mut buf = Vec::new();
+        let x = Value::Array(Array::new(vec![v.clone(), v.clone()]));
+        let mut sig = x.get_type();
+        x.dbus_encode(&mut buf);
+        let mut offset = 0;
+        assert_eq!(demarshal(&mut buf, &mut offset, &mut sig).unwrap(), x);
+        assert_eq!(offset, buf.len());
+    }
+
+    #[test]
+    fn test_reserved_types() {
+        for c in "m*?@&^rez".chars() {
+            let sigs = vec![
+                c.to_string(),
+                "a".to_string() + &c.to_string(),
+                "(".to_string() + &c.to_string() + ")",
+                "a{s".to_string() + &c.to_string() + "}",
+            ];
+            for s in sigs {
+                let mut buf = vec![0; 32];
+                let mut offset = 0;
+                let mut sig = s.clone();
+                match demarshal(&mut buf, &mut offset, &mut sig) {
+                    Err(DemarshalError::BadSignature) => (),
+                    x => panic!("Expected BadSignature for {:?}, got {:?}", s, x)
+                };
+            }
+
+            // A variant whose signature holds the reserved type.  The bad signature is in the data
+            // rather than the one passed in, so this is InvalidSignature with its offset instead
+            // of BadSignature
+            let mut buf = vec![1, c as u8, 0, 0, 0, 0, 0, 0];
+            let mut offset = 0;
+            let mut sig = "v".to_string();
+            match demarshal(&mut buf, &mut offset, &mut sig) {
+                Err(DemarshalError::InvalidSignature { offset: 1 }) => (),
+                x => panic!("Expected InvalidSignature for variant {:?}, got {:?}", c, x)
+            };
+        }
+
+        // Empty and malformed signatures shouldn't panic either
+        for s in vec!["", "a", "a{s}", "a{vs}"] {
+            let mut buf = vec![0; 32];
+            let mut offset = 0;
+            let mut sig = s.to_string();
+            match demarshal(&mut buf, &mut offset, &mut sig) {
+                Err(DemarshalError::BadSignature) => (),
+                x => panic!("Expected BadSignature for {:?}, got {:?}", s, x)
+            };
+        }
+    }
+
+    #[test]
+    fn test_unix_fd() {
+        assert_eq!(get_alignment('h'), 4);
+
+        // An fd index is aligned like a u32
+        let mut buf = vec![0, 0, 0, 0, 2, 0, 0, 0];
+        let mut offset = 1;
+        let mut sig = "h".to_string();
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v, Value::BasicValue(BasicValue::Uint32(2)));
+        assert_eq!(offset, buf.len());
+
+        let mut buf = vec![1, b'h', 0, 0, 2, 0, 0, 0];
+        let mut offset = 0;
+        let mut sig = "v".to_string();
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v, Value::Variant(Variant::new(Value::from(2 as u32), "h")));
+        assert_eq!(offset, buf.len());
+
+        let mut buf = vec![0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0];
+        let mut offset = 1;
+        let mut sig = "ah".to_string();
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v.get_signature(), "ah");
+        match v {
+            Value::Array(x) => assert_eq!(x.objects, vec![Value::from(0 as u32), Value::from(1 as u32)]),
+            x => panic!("Expected an array, got {:?}", x)
+        };
+        assert_eq!(offset, buf.len());
+
+        // Like the other basic types, it can be a dictionary key
+        let mut buf = vec![0; 8];
+        let mut offset = 0;
+        let mut sig = "a{hs}".to_string();
+        demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+    }
+
+    #[test]
+    fn test_standalone_dict_entry() {
+        for s in vec!["{sv}", "({sv})", "v"] {
+            let mut buf = vec![4, b'{', b's', b'v', b'}', 0, 0, 0, 0, 0, 0, 0, 1, b'a', 0, 0, 1, b'y', 0, 5];
+            let mut offset = 0;
+            let mut sig = s.to_string();
+            match demarshal(&mut buf, &mut offset, &mut sig) {
+                Err(DemarshalError::BadSignature) => (),
+                x => panic!("Expected BadSignature for {:?}, got {:?}", s, x)
+            };
+        }
+    }
+
+    #[test]
+    fn test_bad_strings() {
+        // The offset is that of the first invalid byte
+        let mut buf = vec![0, 0, 0, 0, 4, 0, 0, 0, b'a', b'b', 0xff, b'c', 0];
+        let mut offset = 4;
+        let mut sig = "s".to_string();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::BadUTF8 { offset: 10 }) => (),
+            x => panic!("Expected BadUTF8, got {:?}", x)
+        };
+
+        for &(path, bad) in [("/com/te-st", 7), ("com", 0), ("/com//test", 5), ("/com/", 4), ("", 0)].iter() {
+            let mut buf = Vec::new();
+            Path(path.to_owned()).dbus_encode(&mut buf);
+            let mut offset = 0;
+            let mut sig = "o".to_string();
+            match demarshal(&mut buf, &mut offset, &mut sig) {
+                Err(DemarshalError::InvalidObjectPath { offset }) => assert_eq!(offset, 4 + bad, "{}", path),
+                x => panic!("Expected InvalidObjectPath for {:?}, got {:?}", path, x)
+            };
+        }
+        for path in vec!["/", "/com", "/com/test_1"] {
+            let mut buf = Vec::new();
+            Path(path.to_owned()).dbus_encode(&mut buf);
+            let mut offset = 0;
+            let mut sig = "o".to_string();
+            demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        }
+
+        let mut buf = vec![3, b'a', b'!', b'y', 0];
+        let mut offset = 0;
+        let mut sig = "g".to_string();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::InvalidSignature { offset: 2 }) => (),
+            x => panic!("Expected InvalidSignature, got {:?}", x)
+        };
+    }
+
+    #[test]
+    fn test_empty_array() {
+        // An empty array only consumes its own element type from the signature
+        let mut buf = vec![0, 0, 0, 0, 5, 0, 0, 0];
+        let mut offset = 0;
+        let mut sig = "aui".to_string();
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v.get_signature(), "au");
+        assert_eq!(sig, "i");
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(v, Value::BasicValue(BasicValue::Int32(5)));
+    }
+
+    #[test]
+    fn test_framed_message_len() {
+        let msg = message::create_method_call("foo", "/bar", "baz", "floob")
+            .add_arg(&"hello");
+        let mut buf = Vec::new();
+        msg.dbus_encode(&mut buf);
+        assert_eq!(buf.len() % 8, 0);
+        buf.extend_from_slice(&msg.body);
+        let len = buf.len();
+
+        // A second message following the first doesn't change the result
+        buf.extend_from_slice(&[b'l', 1, 0, 1]);
+        assert_eq!(framed_message_len(&buf).unwrap(), len);
+
+        match framed_message_len(&buf[..len - 1]) {
+            Err(DemarshalError::MessageTooShort { offset }) => assert_eq!(offset, len - 1),
+            x => panic!("Expected MessageTooShort, got {:?}", x)
+        };
+        match framed_message_len(&buf[..10]) {
+            Err(DemarshalError::MessageTooShort { offset }) => assert_eq!(offset, 10),
+            x => panic!("Expected MessageTooShort, got {:?}", x)
+        };
+
+        buf[0] = b'x';
+        match framed_message_len(&buf) {
+            Err(DemarshalError::CorruptedMessage { offset }) => assert_eq!(offset, 0),
+            x => panic!("Expected CorruptedMessage, got {:?}", x)
+        };
+    }
+
+    #[test]
+    fn test_array_each() {
+        let array = vec!["foo", "bar", "baz"];
+        let mut buf = Vec::new();
+        array.dbus_encode(&mut buf);
+        buf.push(7);
+
+        let mut offset = 0;
+        let mut sig = "asy".to_owned();
+        let mut seen = Vec::new();
+        demarshal_array_each(&mut buf, &mut offset, &mut sig, |x| seen.push(x)).unwrap();
+        assert_eq!(seen, vec![Value::from("foo"), Value::from("bar"), Value::from("baz")]);
+        assert_eq!(offset, buf.len() - 1);
+        assert_eq!(sig, "y");
+
+        let mut sig = "s".to_owned();
+        assert!(demarshal_array_each(&mut buf, &mut offset, &mut sig, |_| ()).is_err());
+    }
+
+    #[test]
+    fn test_decode_standalone() {
+        let mut buf = Vec::new();
+        (42 as u32).dbus_encode(&mut buf);
+        "foo".dbus_encode(&mut buf);
+        let values = decode_standalone("us", &buf).unwrap();
+        assert_eq!(values, vec![Value::from(42 as u32), Value::from("foo")]);
+
+        let mut map = HashMap::new();
+        map.insert("a", Variant::new(Value::from(1 as u32), "u"));
+        map.insert("b", Variant::new(Value::from("x"), "s"));
+        let mut buf = Vec::new();
+        map.dbus_encode(&mut buf);
+        let values = decode_standalone("a{sv}", &buf).unwrap();
+        assert_eq!(values.len(), 1);
+        let dict = match values[0] {
+            Value::Dictionary(ref x) => x,
+            ref x => panic!("Expected a dictionary, got {:?}", x)
+        };
+        assert_eq!(dict.map.len(), 2);
+        assert_eq!(dict.map[&BasicValue::String("b".to_owned())],
+                   Value::Variant(Variant::new(Value::from("x"), "s")));
+
+        assert_eq!(decode_standalone("", &[]).unwrap(), vec![]);
+        assert!(decode_standalone("uu", &buf[..4]).is_err());
+    }
+
+    #[test]
+    fn test_dict_ordered() {
+        // Encode the entries by hand, since a HashMap wouldn't keep them in order
+        let mut buf = Vec::new();
+        (0 as u32).dbus_encode(&mut buf);
+        for &(k, v) in [(3 as u8, 30 as u32), (1, 10), (2, 20)].iter() {
+            let st = Struct {
+                objects: vec![Value::from(k), Value::from(v)],
+                signature: Signature("{yu}".to_owned())
+            };
+            st.dbus_encode(&mut buf);
+        }
+        let len = (buf.len() - 8) as u32;
+        buf[0] = len as u8;
+
+        let mut offset = 0;
+        let mut sig = "a{yu}".to_owned();
+        let entries = demarshal_dict_ordered(&mut buf, &mut offset, &mut sig).unwrap();
+        assert_eq!(entries, vec![(BasicValue::Byte(3), Value::from(30 as u32)),
+                                 (BasicValue::Byte(1), Value::from(10 as u32)),
+                                 (BasicValue::Byte(2), Value::from(20 as u32))]);
+        assert_eq!(sig, "");
+
+        let mut sig = "au".to_owned();
+        assert!(demarshal_dict_ordered(&mut buf, &mut offset, &mut sig).is_err());
+    }
+
+    #[test]
+    fn test_big_endian_each() {
+        let array = vec![1 as u32, 0x01020304];
+        let mut buf = Vec::new();
+        array.dbus_encode_with_order(&mut buf, Endianness::Big);
+
+        let mut offset = 0;
+        let mut sig = "au".to_owned();
+        let mut seen = Vec::new();
+        demarshal_array_each_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big, |x| seen.push(x)).unwrap();
+        assert_eq!(seen, vec![Value::from(1 as u32), Value::from(0x01020304 as u32)]);
+        assert_eq!(offset, buf.len());
+
+        let mut buf = Vec::new();
+        (0 as u32).dbus_encode_with_order(&mut buf, Endianness::Big);
+        for &(k, v) in [(2 as u8, 0x0a0b0c0d as u32), (1, 10)].iter() {
+            let st = Struct {
+                objects: vec![Value::from(k), Value::from(v)],
+                signature: Signature("{yu}".to_owned())
+            };
+            st.dbus_encode_with_order(&mut buf, Endianness::Big);
+        }
+        let len = (buf.len() - 8) as u32;
+        buf[3] = len as u8;
+
+        let mut offset = 0;
+        let mut sig = "a{yu}".to_owned();
+        let entries = demarshal_dict_ordered_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big).unwrap();
+        assert_eq!(entries, vec![(BasicValue::Byte(2), Value::from(0x0a0b0c0d as u32)),
+                                 (BasicValue::Byte(1), Value::from(10 as u32))]);
+        assert_eq!(offset, buf.len());
+    }
+
+    #[test]
+    fn test_variant_extra_signature() {
+        let v = Variant::new(Value::from(42 as u32), "uu");
+        let mut buf = Vec::new();
+        v.dbus_encode(&mut buf);
+        (7 as u32).dbus_encode(&mut buf);
+
+        let mut offset = 0;
+        let mut sig = "v".to_owned();
+        match demarshal(&mut buf, &mut offset, &mut sig) {
+            Err(DemarshalError::BadSignature) => (),
+            x => panic!("Expected BadSignature, got {:?}", x)
+        };
+    }
+
+    #[test]
+    fn test_large_array() {
+        use std::time::{Duration,Instant};
+
+        // Decoding used to shift the rest of the buffer down for every byte read, which made
+        // this take minutes
+        let x : Vec<u32> = (0..100000).collect();
+        let mut buf = Vec::new();
+        x.dbus_encode(&mut buf);
+
+        let start = Instant::now();
+        let mut offset = 0;
+        let mut sig = "au".to_owned();
+        let v = demarshal(&mut buf, &mut offset, &mut sig).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let arr = match v {
+            Value::Array(x) => x,
+            _ => panic!("Bad return from demarshal {:?}", v)
+        };
+        assert_eq!(arr.objects.len(), 100000);
+        assert_eq!(arr.objects[99999], Value::from(99999 as u32));
+        assert_eq!(offset, buf.len());
+    }
 }
@@ -5,17 +5,23 @@ extern crate dbus_serialize;
 extern crate rustc_serialize;
 extern crate unix_socket;
 extern crate libc;
+#[cfg(feature = "cookie-auth")]
 extern crate rand;
+#[cfg(feature = "cookie-auth")]
 extern crate crypto;
 
 pub mod demarshal;
 pub mod marshal;
 pub mod message;
 pub mod connection;
+pub mod proxy;
+#[cfg(feature = "async")]
+pub mod stream;
 
 mod address;
 pub mod addr {
     pub use address::UnescapeError;
     pub use address::Error as AddressError;
     pub use address::ServerAddressError;
+    pub use address::{ServerAddress,UnixAddress,TcpAddress,NonceTcpAddress};
 }
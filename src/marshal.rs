@@ -4,12 +4,56 @@ use std::collections::HashMap;
 
 use dbus_serialize::types::{Value,BasicValue,Path,Signature,Struct,Variant};
 
+use demarshal::{demarshal,get_alignment,single_type_len,DemarshalError};
+use message::Endianness;
+
+/// Types that can be encoded as D-Bus data.
+///
+/// D-Bus has no signed byte type, so i8 deliberately doesn't implement Marshal.  Wrap it in
+/// SignedByte to send it as an unsigned byte instead.
 pub trait Marshal {
     /// Encodes itself into buf, and returns the number of bytes written excluding leading padding
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize;
 
     /// Returns the D-Bus type signature for this object
     fn get_type(&self) -> String;
+
+    /// Encodes itself into buf in the given byte order, and returns the number of bytes written
+    /// excluding leading padding.  dbus_encode always uses little-endian.
+    fn dbus_encode_with_order(&self, buf: &mut Vec<u8>, order: Endianness) -> usize {
+        let mut offset = buf.len();
+        let len = self.dbus_encode(buf);
+        if order == Endianness::Big {
+            swap_byte_order(buf, &mut offset, &mut self.get_type(), Endianness::Little);
+        }
+        len
+    }
+
+    /// Returns this object as a dbus_serialize Value, so that it can be placed in a Variant or
+    /// a Dictionary.  The default implementation calls try_to_value; types with a direct Value
+    /// representation override it.
+    ///
+    /// # Panics
+    /// The default implementation panics if try_to_value fails.
+    fn to_value(&self) -> Value {
+        self.try_to_value().expect("Marshal produced undecodable data")
+    }
+
+    /// Like to_value, but encodes the object and demarshals the result, so data that isn't valid
+    /// D-Bus, such as a Path that isn't a valid object path, is reported instead of panicking.
+    fn try_to_value(&self) -> Result<Value,DemarshalError> {
+        let mut buf = Vec::new();
+        self.dbus_encode(&mut buf);
+        let mut offset = 0;
+        let mut sig = self.get_type();
+        demarshal(&mut buf, &mut offset, &mut sig)
+    }
+
+    /// Returns the signature that every value of this type has, or None if it depends on the
+    /// value, as for a Struct or a Value.  Empty containers use it to find their signature.
+    fn type_signature() -> Option<String> where Self: Sized {
+        None
+    }
 }
 
 // Saying a type implements BasicMarshal is a promise to the type system that it can be used as the
@@ -23,6 +67,74 @@ pub fn pad_to_multiple (buf: &mut Vec<u8>, len: usize) -> () {
     }
 }
 
+/// Rounds len up to a multiple of align
+pub(crate) fn align_len(len: usize, align: usize) -> usize {
+    (len + align - 1) / align * align
+}
+
+/// Reverses the byte order of an integer of len bytes at offset, and moves offset past it.
+/// Returns the integer's value, read in the order from.
+fn swap_int(buf: &mut [u8], offset: &mut usize, len: usize, from: Endianness) -> u64 {
+    let bytes = &mut buf[*offset..*offset + len];
+    if from == Endianness::Little {
+        bytes.reverse();
+    }
+    let x = bytes.iter().fold(0, |x, &b| (x << 8) | b as u64);
+    if from == Endianness::Big {
+        bytes.reverse();
+    }
+    *offset += len;
+    x
+}
+
+/// Converts the first complete type in sig, which is encoded at offset in buf in the order from,
+/// to the other byte order, and removes it from sig.  offset is moved past the data.  As with
+/// dbus_encode, alignment is relative to the start of buf.
+///
+/// # Panics
+/// Panics if the data isn't valid for sig, which can't happen for data produced by dbus_encode.
+pub(crate) fn swap_byte_order(buf: &mut [u8], offset: &mut usize, sig: &mut String, from: Endianness) {
+    let typ = sig.remove(0);
+    *offset = align_len(*offset, get_alignment(typ));
+    match typ {
+        'y' => *offset += 1,
+        'g' => *offset += buf[*offset] as usize + 2,
+        'n' | 'q' => { swap_int(buf, offset, 2, from); },
+        'b' | 'i' | 'u' | 'h' => { swap_int(buf, offset, 4, from); },
+        'x' | 't' | 'd' => { swap_int(buf, offset, 8, from); },
+        's' | 'o' => {
+            let len = swap_int(buf, offset, 4, from) as usize;
+            *offset += len + 1;
+        },
+        'a' => {
+            let len = swap_int(buf, offset, 4, from) as usize;
+            let elem_len = single_type_len(sig).expect("Bad array signature");
+            let elem_sig : String = sig.drain(..elem_len).collect();
+            *offset = align_len(*offset, get_alignment(elem_sig.chars().next().unwrap()));
+            let end = *offset + len;
+            while *offset < end {
+                swap_byte_order(buf, offset, &mut elem_sig.clone(), from);
+            }
+        },
+        '(' | '{' => {
+            while !sig.starts_with(')') && !sig.starts_with('}') {
+                swap_byte_order(buf, offset, sig, from);
+            }
+            sig.remove(0);
+        },
+        'v' => {
+            let len = buf[*offset] as usize;
+            let mut var_sig = String::from_utf8(buf[*offset + 1..*offset + 1 + len].to_vec())
+                .expect("Bad variant signature");
+            *offset += len + 2;
+            while !var_sig.is_empty() {
+                swap_byte_order(buf, offset, &mut var_sig, from);
+            }
+        },
+        _ => panic!("Bogus type")
+    }
+}
+
 fn marshal_int (x: u64, len: usize, buf: &mut Vec<u8>) -> usize {
     pad_to_multiple(buf, len);
 
@@ -39,15 +151,15 @@ fn marshal_int (x: u64, len: usize, buf: &mut Vec<u8>) -> usize {
     len
 }
 
-// Same as above except we don't convert to little-endian
 fn marshal_double (x: f64, buf: &mut Vec<u8>) -> usize {
-    let len = 8;
-    pad_to_multiple(buf, len);
+    marshal_int(x.to_bits(), 8, buf)
+}
 
-    let bytes : [u8; 8] = unsafe { transmute(x) };
-    for i in 0..len {
-        buf.push(bytes[i]);
-    }
+/// Encodes an empty array whose signature is sig.  Even without any elements, the length is
+/// followed by padding up to the alignment of the element type.
+fn marshal_empty_array (sig: &str, buf: &mut Vec<u8>) -> usize {
+    let len = (0 as u32).dbus_encode(buf);
+    pad_to_multiple(buf, get_alignment(sig[1..].chars().next().unwrap()));
     len
 }
 
@@ -81,10 +193,38 @@ impl Marshal for u8 {
     fn get_type (&self) -> String {
         "y".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Byte(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("y".to_owned())
+    }
 }
 
 impl BasicMarshal for u8 { }
 
+/// An i8 to be sent as a D-Bus byte ('y').  D-Bus bytes are unsigned, so the value is sent with
+/// the same bits reinterpreted as a u8, and the receiver sees e.g. -1 as 255.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct SignedByte(pub i8);
+
+impl Marshal for SignedByte {
+    fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
+        (self.0 as u8).dbus_encode(buf)
+    }
+    fn get_type (&self) -> String {
+        "y".to_owned()
+    }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Byte(self.0 as u8))
+    }
+    fn type_signature() -> Option<String> {
+        Some("y".to_owned())
+    }
+}
+
+impl BasicMarshal for SignedByte { }
+
 impl Marshal for bool {
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
         let val = match *self {
@@ -96,6 +236,12 @@ impl Marshal for bool {
     fn get_type (&self) -> String {
         "b".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Boolean(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("b".to_owned())
+    }
 }
 impl BasicMarshal for bool { }
 
@@ -106,6 +252,12 @@ impl Marshal for i16 {
     fn get_type (&self) -> String {
         "n".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Int16(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("n".to_owned())
+    }
 }
 impl BasicMarshal for i16 { }
 
@@ -116,6 +268,12 @@ impl Marshal for u16 {
     fn get_type (&self) -> String {
         "q".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Uint16(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("q".to_owned())
+    }
 }
 impl BasicMarshal for u16 { }
 
@@ -126,6 +284,12 @@ impl Marshal for i32 {
     fn get_type (&self) -> String {
         "i".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Int32(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("i".to_owned())
+    }
 }
 impl BasicMarshal for i32 { }
 
@@ -136,6 +300,12 @@ impl Marshal for u32 {
     fn get_type (&self) -> String {
         "u".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Uint32(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("u".to_owned())
+    }
 }
 impl BasicMarshal for u32 { }
 
@@ -146,6 +316,12 @@ impl Marshal for i64 {
     fn get_type (&self) -> String {
         "x".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Int64(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("x".to_owned())
+    }
 }
 impl BasicMarshal for i64 { }
 
@@ -156,6 +332,12 @@ impl Marshal for u64 {
     fn get_type (&self) -> String {
         "t".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Uint64(*self))
+    }
+    fn type_signature() -> Option<String> {
+        Some("t".to_owned())
+    }
 }
 impl BasicMarshal for u64 { }
 
@@ -166,6 +348,12 @@ impl Marshal for f64 {
     fn get_type (&self) -> String {
         "d".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::Double(*self)
+    }
+    fn type_signature() -> Option<String> {
+        Some("d".to_owned())
+    }
 }
 impl BasicMarshal for f64 { }
 
@@ -176,6 +364,12 @@ impl<'a> Marshal for &'a str {
     fn get_type (&self) -> String {
         "s".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::String((*self).to_owned()))
+    }
+    fn type_signature() -> Option<String> {
+        Some("s".to_owned())
+    }
 }
 impl<'a> Marshal for String {
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
@@ -184,8 +378,15 @@ impl<'a> Marshal for String {
     fn get_type (&self) -> String {
         "s".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::String(self.to_owned()))
+    }
+    fn type_signature() -> Option<String> {
+        Some("s".to_owned())
+    }
 }
 impl<'a> BasicMarshal for &'a str { }
+impl BasicMarshal for String { }
 
 impl Marshal for Path {
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
@@ -194,6 +395,12 @@ impl Marshal for Path {
     fn get_type (&self) -> String {
         "o".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::ObjectPath(self.clone()))
+    }
+    fn type_signature() -> Option<String> {
+        Some("o".to_owned())
+    }
 }
 impl BasicMarshal for Path { }
 
@@ -202,7 +409,13 @@ impl Marshal for Signature {
         marshal_signature(self.0.to_owned(), buf)
     }
     fn get_type (&self) -> String {
-        "o".to_owned()
+        "g".to_owned()
+    }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(BasicValue::Signature(self.clone()))
+    }
+    fn type_signature() -> Option<String> {
+        Some("g".to_owned())
     }
 }
 impl BasicMarshal for Signature { }
@@ -220,30 +433,61 @@ impl Marshal for Struct {
     fn get_type(&self) -> String {
         self.signature.0.to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::Struct(self.clone())
+    }
 }
 
-impl<T: Marshal> Marshal for Vec<T> {
-    fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
-        // Encode a length of 0 as a place-holder since we don't know the real length yet
-        let mut array_len = 0 as u32;
-        array_len.dbus_encode(buf);
-        let start_len = buf.len();
-        let len_idx = start_len - 4;
-        for x in self {
-            x.dbus_encode(buf);
+/// Encodes items as an array whose signature is sig
+fn marshal_array<T: Marshal>(items: &[T], sig: &str, buf: &mut Vec<u8>) -> usize {
+    if items.is_empty() {
+        return marshal_empty_array(sig, buf);
+    }
+    // Encode a length of 0 as a place-holder since we don't know the real length yet
+    let mut array_len = 0 as u32;
+    array_len.dbus_encode(buf);
+    let start_len = buf.len();
+    let len_idx = start_len - 4;
+    // The padding between the length and the first element isn't part of the array length.
+    // dbus_encode doesn't count leading padding in its return value, so use that to find it.
+    let mut leading_pad = 0;
+    for (i, x) in items.iter().enumerate() {
+        let before = buf.len();
+        let len = x.dbus_encode(buf);
+        if i == 0 {
+            leading_pad = buf.len() - before - len;
         }
-        array_len = (buf.len() - start_len) as u32;
+    }
+    array_len = (buf.len() - start_len - leading_pad) as u32;
 
-        // Update the encoded length with the real value
-        let mut len_buf = Vec::new();
-        array_len.dbus_encode(&mut len_buf);
-        for i in 0..4 {
-            buf[len_idx+i] = len_buf[i];
-        }
-        (array_len as usize) + 4
+    // Update the encoded length with the real value
+    let mut len_buf = Vec::new();
+    array_len.dbus_encode(&mut len_buf);
+    for i in 0..4 {
+        buf[len_idx+i] = len_buf[i];
+    }
+    (array_len as usize) + 4
+}
+
+/// An array's signature is taken from its first element.  An empty one needs its element type to
+/// have a type_signature.
+///
+/// # Panics
+/// get_type and dbus_encode panic for an empty Vec of a type without a type_signature, such as
+/// Struct or Value.  Use a Value::Array made with Array::new_with_sig to give it a signature
+/// explicitly.
+impl<T: Marshal> Marshal for Vec<T> {
+    fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
+        marshal_array(self, &self.get_type(), buf)
     }
     fn get_type(&self) -> String {
-        "a".to_owned() + &(self.iter().next().unwrap().get_type())
+        match self.first() {
+            Some(x) => "a".to_owned() + &x.get_type(),
+            None => Self::type_signature().expect("Empty array of a type with no fixed signature")
+        }
+    }
+    fn type_signature() -> Option<String> {
+        T::type_signature().map(|x| "a".to_owned() + &x)
     }
 }
 
@@ -267,19 +511,42 @@ impl<K,V> Marshal for DictEntry<K, V>
     }
 }
 
+/// Encodes map as an array of dict entries whose signature is sig
+fn marshal_dict<K,V>(map: &HashMap<K, V>, sig: &str, buf: &mut Vec<u8>) -> usize
+        where K: Clone + Hash + Eq + BasicMarshal,
+              V: Clone + Marshal {
+    // Convert the map to an array of DictEntry
+    let mut array = Vec::new();
+    for (key, value) in map {
+        array.push(DictEntry{key: key.clone(), value: value.clone()});
+    }
+    marshal_array(&array, sig, buf)
+}
+
+/// As with Vec, a dictionary's signature is taken from its entries, and an empty one needs its
+/// key and value types to have a type_signature.
+///
+/// # Panics
+/// get_type and dbus_encode panic for an empty HashMap whose values have no type_signature, such
+/// as HashMap<String, Value>.  Use HashMap<String, Variant> for an a{sv}, or a Value::Dictionary
+/// made with Dictionary::new_with_sig.
 impl<K,V> Marshal for HashMap<K, V>
         where K: Clone + Hash + Eq + BasicMarshal,
               V: Clone + Marshal {
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
-        // Convert the map to an array of DictEntry
-        let mut array = Vec::new();
-        for (key, value) in self {
-            array.push(DictEntry{key: key.clone(), value: value.clone()});
-        }
-        array.dbus_encode(buf)
+        marshal_dict(self, &self.get_type(), buf)
     }
     fn get_type(&self) -> String {
-        "a".to_owned() + "{" + &self.keys().next().unwrap().get_type() + &self.values().next().unwrap().get_type() + "}"
+        match self.iter().next() {
+            Some((k, v)) => "a".to_owned() + "{" + &k.get_type() + &v.get_type() + "}",
+            None => Self::type_signature().expect("Empty dictionary of types with no fixed signature")
+        }
+    }
+    fn type_signature() -> Option<String> {
+        match (K::type_signature(), V::type_signature()) {
+            (Some(k), Some(v)) => Some("a".to_owned() + "{" + &k + &v + "}"),
+            _ => None
+        }
     }
 }
 
@@ -295,8 +562,15 @@ impl Marshal for Variant {
     fn get_type(&self) -> String {
         "v".to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::Variant(self.clone())
+    }
+    fn type_signature() -> Option<String> {
+        Some("v".to_owned())
+    }
 }
 
+
 impl Marshal for BasicValue {
     fn dbus_encode(&self, buf: &mut Vec<u8>) -> usize {
         match *self {
@@ -317,6 +591,9 @@ impl Marshal for BasicValue {
     fn get_type(&self) -> String {
         self.get_signature().to_owned()
     }
+    fn to_value(&self) -> Value {
+        Value::BasicValue(self.clone())
+    }
 }
 
 impl BasicMarshal for BasicValue { }
@@ -326,16 +603,180 @@ impl Marshal for Value {
         match *self {
             Value::BasicValue(ref x) => x.dbus_encode(buf),
             Value::Double(ref x) => x.dbus_encode(buf),
-            Value::Array(ref x) => x.objects.dbus_encode(buf),
+            Value::Array(ref x) => marshal_array(&x.objects, self.get_signature(), buf),
             Value::Variant(ref x) => x.dbus_encode(buf),
             Value::Struct(ref x) => x.dbus_encode(buf),
-            Value::Dictionary(ref x) => x.map.dbus_encode(buf)
+            Value::Dictionary(ref x) => marshal_dict(&x.map, self.get_signature(), buf)
         }
     }
 
     fn get_type(&self) -> String {
         self.get_signature().to_owned()
     }
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
+
+/// Encodes value starting at offset start in the buffer, and checks that what was written,
+/// including any leading padding, is expected.
+#[cfg(test)]
+fn assert_marshaled(value: &Marshal, start: usize, expected: &[u8]) {
+    let mut buf = vec![0xff; start];
+    value.dbus_encode(&mut buf);
+    assert_eq!(&buf[start..], expected, "encoding {} at offset {}", value.get_type(), start);
+}
+
+#[test]
+fn test_alignment () {
+    assert_marshaled(&(7 as u8), 1, &[7]);
+    assert_marshaled(&true, 1, &[0, 0, 0, 1, 0, 0, 0]);
+    assert_marshaled(&(0x1234 as i16), 1, &[0, 0x34, 0x12]);
+    assert_marshaled(&(0x1234 as u16), 2, &[0x34, 0x12]);
+    assert_marshaled(&(-2 as i32), 1, &[0, 0, 0, 0xfe, 0xff, 0xff, 0xff]);
+    assert_marshaled(&(42 as u32), 5, &[0, 0, 0, 42, 0, 0, 0]);
+    assert_marshaled(&(42 as i64), 1, &[0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_marshaled(&(42 as u64), 8, &[42, 0, 0, 0, 0, 0, 0, 0]);
+    assert_marshaled(&1.0f64, 3, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+    assert_marshaled(&"hi", 1, &[0, 0, 0, 2, 0, 0, 0, b'h', b'i', 0]);
+    assert_marshaled(&Path("/a".to_owned()), 6, &[0, 0, 2, 0, 0, 0, b'/', b'a', 0]);
+    assert_marshaled(&Signature("iu".to_owned()), 1, &[2, b'i', b'u', 0]);
+
+    // The variant's value is aligned relative to the start of the buffer, not of the variant
+    let v = Variant::new(Value::from(42 as u32), "u");
+    assert_marshaled(&v, 1, &[1, b'u', 0, 42, 0, 0, 0]);
+    assert_marshaled(&v, 2, &[1, b'u', 0, 0, 0, 0, 42, 0, 0, 0]);
+
+    // Array lengths don't include the padding before the first element
+    assert_marshaled(&vec![1 as u32, 2], 1, &[0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    assert_marshaled(&vec![1 as u64], 0, &[8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_marshaled(&vec![1 as u64], 4, &[8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+
+    let st = Struct {
+        objects: vec![Value::from(5 as u8), Value::from(42 as u32)],
+        signature: Signature("(yu)".to_owned())
+    };
+    assert_marshaled(&st, 1, &[0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 42, 0, 0, 0]);
+    assert_marshaled(&st, 8, &[5, 0, 0, 0, 42, 0, 0, 0]);
+
+    let mut map = HashMap::new();
+    map.insert(3 as u8, 9 as u32);
+    assert_marshaled(&map, 1, &[0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0, 9, 0, 0, 0]);
+}
+
+#[test]
+fn test_big_endian () {
+    use demarshal::demarshal_with_order;
+
+    let mut buf = vec![0];
+    assert_eq!((0x01020304 as u32).dbus_encode_with_order(&mut buf, Endianness::Big), 4);
+    assert_eq!(buf, vec![0, 0, 0, 0, 1, 2, 3, 4]);
+
+    let mut map = HashMap::new();
+    map.insert("half", 0.5);
+    let st = Struct {
+        objects: vec![
+            Value::from(5 as u8),
+            Value::from(0x1234 as u16),
+            Value::from("hello"),
+            Value::from(true),
+            vec![1 as u32, 2].to_value(),
+            Value::Variant(Variant::new(Value::from(7 as u64), "t")),
+            map.to_value(),
+        ],
+        signature: Signature("(yqsbauva{sd})".to_owned())
+    };
+    let mut buf = vec![0];
+    st.dbus_encode_with_order(&mut buf, Endianness::Big);
+    assert_eq!(&buf[8..13], &[5, 0, 0x12, 0x34, 0]);
+    let mut offset = 1;
+    let mut sig = st.get_type();
+    let v = demarshal_with_order(&mut buf, &mut offset, &mut sig, Endianness::Big).unwrap();
+    assert_eq!(v, Value::Struct(st));
+    assert_eq!(offset, buf.len());
+}
+
+#[test]
+fn test_empty_containers () {
+    let v : Vec<u32> = Vec::new();
+    assert_eq!(v.get_type(), "au");
+    assert_marshaled(&v, 1, &[0, 0, 0, 0, 0, 0, 0]);
+    let v : Vec<u64> = Vec::new();
+    assert_eq!(v.get_type(), "at");
+    assert_marshaled(&v, 0, &[0, 0, 0, 0, 0, 0, 0, 0]);
+    let v : Vec<Vec<String>> = Vec::new();
+    assert_eq!(v.get_type(), "aas");
+
+    let map : HashMap<String, Variant> = HashMap::new();
+    assert_eq!(map.get_type(), "a{sv}");
+    assert_marshaled(&map, 0, &[0, 0, 0, 0, 0, 0, 0, 0]);
+    let map : HashMap<u32, Vec<Path>> = HashMap::new();
+    assert_eq!(map.get_type(), "a{uao}");
+
+    // Non-empty containers still take their type from their contents
+    assert_eq!(vec![Value::from(1 as u32)].get_type(), "au");
+
+    let msg = ::message::create_method_return(1).add_arg(&Vec::<String>::new());
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Vec::<String>::new().to_value()]);
+}
+
+#[test]
+fn test_array_of_dynamic_types () {
+    // Types without a fixed signature can still be put in a non-empty array
+    let st = Struct {
+        objects: vec![Value::from(1 as u32), Value::from("a")],
+        signature: Signature("(us)".to_owned())
+    };
+    let v = vec![st.clone(), st];
+    assert_eq!(v.get_type(), "a(us)");
+    assert_marshaled(&v, 0, &[26, 0, 0, 0, 0, 0, 0, 0,
+                              1, 0, 0, 0, 1, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0,
+                              1, 0, 0, 0, 1, 0, 0, 0, b'a', 0]);
+    let v = vec![BasicValue::Uint32(1), BasicValue::Uint32(2)];
+    assert_eq!(v.get_type(), "au");
+    assert_marshaled(&v, 0, &[8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+}
+
+#[test]
+#[should_panic]
+fn test_empty_array_of_values () {
+    // Like a non-empty one, an empty Vec<Value> has no signature of its own
+    Vec::<Value>::new().get_type();
+}
+
+#[test]
+fn test_variant_container () {
+    use dbus_serialize::types::{Array,Dictionary};
+
+    // Empty arrays still pad up to their element type's alignment
+    let v = Variant::new(Value::Array(Array::new_with_sig(vec![], "at".to_owned())), "at");
+    assert_marshaled(&v, 0, &[2, b'a', b't', 0, 0, 0, 0, 0]);
+    assert_marshaled(&v, 1, &[2, b'a', b't', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let v = Variant::new(Value::Array(Array::new_with_sig(vec![], "au".to_owned())), "au");
+    assert_marshaled(&v, 0, &[2, b'a', b'u', 0, 0, 0, 0, 0]);
+    let v = Variant::new(Value::Dictionary(Dictionary::new_with_sig(HashMap::new(), "a{sv}".to_owned())), "a{sv}");
+    assert_marshaled(&v, 0, &[5, b'a', b'{', b's', b'v', b'}', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(v.to_value(), Value::Variant(v.clone()));
+
+    let inner = Struct {
+        objects: vec![Value::from(1 as u8), Value::from("x")],
+        signature: Signature("(ys)".to_owned())
+    };
+    let outer = Struct {
+        objects: vec![Value::Struct(inner), Value::from(2 as u16)],
+        signature: Signature("((ys)q)".to_owned())
+    };
+    let v = Variant::new(Value::Struct(outer), "((ys)q)");
+    assert_marshaled(&v, 0, &[7, b'(', b'(', b'y', b's', b')', b'q', b')', 0,
+                              0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, b'x', 0, 2, 0]);
+
+    // They decode back to the same thing
+    let mut buf = Vec::new();
+    v.dbus_encode(&mut buf);
+    let mut offset = 0;
+    let mut sig = "v".to_owned();
+    assert_eq!(demarshal(&mut buf, &mut offset, &mut sig).unwrap(), Value::Variant(v));
+    assert_eq!(offset, buf.len());
 }
 
 #[test]
@@ -350,6 +791,16 @@ fn test_ints () {
     assert_eq!("u", x.get_type());
 }
 
+#[test]
+fn test_signed_byte () {
+    let mut buf = Vec::new();
+    assert_eq!(SignedByte(-1).dbus_encode(&mut buf), 1);
+    assert_eq!(SignedByte(5).dbus_encode(&mut buf), 1);
+    assert_eq!(buf, vec![255, 5]);
+    assert_eq!(SignedByte(-1).get_type(), "y");
+    assert_eq!(SignedByte(-128).to_value(), Value::BasicValue(BasicValue::Byte(128)));
+}
+
 #[test]
 fn test_string () {
     let x = "abc123";
@@ -360,6 +811,24 @@ fn test_string () {
     assert_eq!(x_buf, x_bytes);
 }
 
+#[test]
+fn test_empty_string () {
+    let mut buf = Vec::new();
+    let len = "".dbus_encode(&mut buf);
+    assert_eq!(len, 5);
+    assert_eq!(buf, vec![0, 0, 0, 0, 0]);
+
+    buf = Vec::new();
+    let len = Path("".to_owned()).dbus_encode(&mut buf);
+    assert_eq!(len, 5);
+    assert_eq!(buf, vec![0, 0, 0, 0, 0]);
+
+    buf = Vec::new();
+    let len = Signature("".to_owned()).dbus_encode(&mut buf);
+    assert_eq!(len, 2);
+    assert_eq!(buf, vec![0, 0]);
+}
+
 #[test]
 fn test_array () {
     //assert_eq!("ay", Vec::<u8>::get_type());
@@ -377,6 +846,13 @@ fn test_array () {
     buf = Vec::new();
     array.dbus_encode(&mut buf);
     assert_eq!(buf, bytes);
+
+    // Padding before the first element is not included in the length
+    let array : Vec<u64> = vec![1];
+    bytes = vec![8, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+    buf = Vec::new();
+    array.dbus_encode(&mut buf);
+    assert_eq!(buf, bytes);
 }
 
 #[test]
@@ -393,3 +869,36 @@ fn test_variant () {
     assert_eq!(len, 8);
     assert_eq!(buf, v_bytes);
 }
+
+#[test]
+fn test_to_value () {
+    assert_eq!(42u32.to_value(), Value::BasicValue(BasicValue::Uint32(42)));
+    assert_eq!("foo".to_value(), Value::BasicValue(BasicValue::String("foo".to_owned())));
+    assert_eq!(1.5f64.to_value(), Value::Double(1.5));
+
+    // Containers go through the default encode/demarshal path
+    let v = vec![1 as u32, 2 as u32].to_value();
+    assert_eq!(v.get_signature(), "au");
+    match v {
+        Value::Array(x) => assert_eq!(x.objects, vec![Value::from(1 as u32), Value::from(2 as u32)]),
+        x => panic!("Bad return from to_value {:?}", x)
+    };
+
+    let mut map = HashMap::new();
+    map.insert("key", 7 as i32);
+    let v = map.to_value();
+    assert_eq!(v.get_signature(), "a{si}");
+
+    // Data that won't demarshal is an error rather than a panic
+    match vec![Path("bad".to_owned())].try_to_value() {
+        Err(DemarshalError::InvalidObjectPath { offset: 8 }) => (),
+        x => panic!("Expected InvalidObjectPath, got {:?}", x)
+    };
+    assert_eq!(vec![Path("/ok".to_owned())].try_to_value().unwrap(), vec![Path("/ok".to_owned())].to_value());
+
+    // Anything marshalable can be boxed up in a Variant
+    let v = Variant::new(map.to_value(), &map.get_type());
+    let mut buf = Vec::new();
+    v.dbus_encode(&mut buf);
+    assert_eq!(&buf[0..6], &[5, 'a' as u8, '{' as u8, 's' as u8, 'i' as u8, '}' as u8]);
+}
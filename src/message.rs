@@ -1,13 +1,19 @@
 //! Functions for creating and modifying messages to send across the message bus.
+use std::fmt;
 use std::ops::DerefMut;
 use std::cell::RefCell;
+use std::os::unix::io::RawFd;
+
+use libc;
 
 use dbus_serialize::types::{Path,Variant,Value,BasicValue,Signature};
 
-use marshal::{Marshal,pad_to_multiple};
-use demarshal::{demarshal,DemarshalError};
+use marshal::{Marshal,pad_to_multiple,align_len,swap_byte_order};
+use dbus_serialize::decoder::DBusDecoder;
+
+use demarshal::{demarshal_with_order,framed_message_len,invalid_path_offset,DemarshalError};
 
-#[derive(Debug,Default,PartialEq,Eq)]
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq)]
 pub struct MessageType(pub u8);
 pub const MESSAGE_TYPE_INVALID : MessageType        = MessageType(0);
 pub const MESSAGE_TYPE_METHOD_CALL : MessageType    = MessageType(1);
@@ -24,10 +30,37 @@ pub const HEADER_FIELD_REPLY_SERIAL: u8 = 5;
 pub const HEADER_FIELD_DESTINATION: u8  = 6;
 pub const HEADER_FIELD_SENDER: u8       = 7;
 pub const HEADER_FIELD_SIGNATURE: u8    = 8;
+pub const HEADER_FIELD_UNIX_FDS: u8     = 9;
 
 pub const FLAGS_NO_REPLY_EXPECTED : u8  = 1;
 
-#[derive(Debug)]
+/// Byte order of a message, as given by the first byte of its header
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// Returns the Endianness for a header's endianness flag, or None if the flag is invalid
+    pub fn from_byte(b: u8) -> Option<Endianness> {
+        match b {
+            b'l' => Some(Endianness::Little),
+            b'B' => Some(Endianness::Big),
+            _ => None
+        }
+    }
+
+    /// Returns the endianness flag to use in a message header
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Endianness::Little => b'l',
+            Endianness::Big => b'B',
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
 pub struct HeaderField (
     pub u8,
     pub Variant
@@ -45,10 +78,88 @@ impl Marshal for HeaderField {
     fn get_type(&self) -> String {
         "(yv)".to_owned()
     }
+    fn type_signature() -> Option<String> {
+        Some("(yv)".to_owned())
+    }
 }
 
-/// Represents a received message from the message bus
+/// File descriptors attached to a Message.  They're owned by the Message, which closes any that
+/// haven't been taken when it's dropped.  Taken descriptors are replaced by -1.
 #[derive(Debug,Default)]
+struct UnixFds(Vec<RawFd>);
+
+impl Clone for UnixFds {
+    fn clone(&self) -> UnixFds {
+        UnixFds(self.0.iter().map(|&fd| {
+            if fd < 0 {
+                fd
+            } else {
+                unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) }
+            }
+        }).collect())
+    }
+}
+
+impl Drop for UnixFds {
+    fn drop(&mut self) {
+        for &fd in self.0.iter().filter(|&&fd| fd >= 0) {
+            unsafe { libc::close(fd); }
+        }
+    }
+}
+
+/// The header fields needed to decide where a message should go.  Fields the message doesn't
+/// have, or that hold the wrong type, are None.
+#[derive(Debug,Default,Clone,PartialEq)]
+pub struct RoutingInfo {
+    pub message_type: MessageType,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+}
+
+/// Iterates over the arguments in a message's body, demarshaling each one only when it's reached.
+/// Created by Message::iter_args.  Iteration stops after the first error.
+pub struct ArgIter {
+    body: Vec<u8>,
+    offset: usize,
+    sig: String,
+    order: Endianness,
+    error: Option<DemarshalError>,
+}
+
+impl Iterator for ArgIter {
+    type Item = Result<Value,DemarshalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.sig.clear();
+            return Some(Err(e));
+        }
+        if self.sig.is_empty() {
+            // Anything left over doesn't belong to any argument
+            if self.offset != self.body.len() {
+                self.offset = self.body.len();
+                return Some(Err(DemarshalError::CorruptedMessage { offset: self.offset }));
+            }
+            return None;
+        }
+        let res = demarshal_with_order(&mut self.body, &mut self.offset, &mut self.sig, self.order);
+        if res.is_err() {
+            self.sig.clear();
+            self.offset = self.body.len();
+        }
+        Some(res)
+    }
+}
+
+/// Represents a received message from the message bus
+#[derive(Debug,Default,Clone)]
 pub struct Message {
     pub big_endian: bool,
     pub message_type: MessageType,
@@ -58,13 +169,13 @@ pub struct Message {
     pub headers: Vec<HeaderField>,
     pub body: Vec<u8>,
 
-    body_cache: RefCell<Option<Result<Option<Vec<Value>>, DemarshalError>>>
+    body_cache: RefCell<Option<Result<Option<Vec<Value>>, DemarshalError>>>,
+    unix_fds: UnixFds,
 }
 
 impl Marshal for Message {
     fn dbus_encode (&self, buf: &mut Vec<u8>) -> usize {
-        let endian = if self.big_endian { 'B' as u8 } else { 'l' as u8 };
-        endian.dbus_encode(buf);
+        self.endianness().to_byte().dbus_encode(buf);
         self.message_type.0.dbus_encode(buf);
         self.flags.dbus_encode(buf);
         self.version.dbus_encode(buf);
@@ -73,6 +184,13 @@ impl Marshal for Message {
         self.serial.dbus_encode(buf);
         self.headers.dbus_encode(buf);
         pad_to_multiple(buf, 8);
+        if self.big_endian {
+            let mut sig = "yyyyuua(yv)".to_owned();
+            let mut offset = 0;
+            while !sig.is_empty() {
+                swap_byte_order(buf, &mut offset, &mut sig, Endianness::Little);
+            }
+        }
         0
     }
 
@@ -81,6 +199,71 @@ impl Marshal for Message {
     }
 }
 
+/// The errors returned by the checked message constructors, holding the name that was rejected
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ValidationError {
+    ObjectPath(String),
+    Interface(String),
+    Member(String),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ValidationError::ObjectPath(ref x) => write!(f, "invalid object path: {:?}", x),
+            ValidationError::Interface(ref x) => write!(f, "invalid interface name: {:?}", x),
+            ValidationError::Member(ref x) => write!(f, "invalid member name: {:?}", x),
+        }
+    }
+}
+
+/// Returns whether path is a valid object path: a '/' followed by elements of [A-Za-z0-9_]
+/// separated by single slashes, with no trailing slash unless the whole path is "/".
+pub fn validate_object_path(path: &str) -> bool {
+    invalid_path_offset(path).is_none()
+}
+
+/// Returns whether name is a valid member name: up to 255 characters of [A-Za-z0-9_], not
+/// starting with a digit.
+pub fn validate_member_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 255 &&
+        !name.starts_with(|c: char| c.is_ascii_digit()) &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns whether name is a valid interface name: up to 255 characters, made of at least two
+/// elements separated by '.', each of which follows the rules for member names.
+pub fn validate_interface_name(name: &str) -> bool {
+    name.len() <= 255 && name.contains('.') && name.split('.').all(validate_member_name)
+}
+
+fn check_names(path: &str, iface: &str, member: &str) -> Result<(),ValidationError> {
+    if !validate_object_path(path) {
+        return Err(ValidationError::ObjectPath(path.to_owned()));
+    }
+    if !validate_interface_name(iface) {
+        return Err(ValidationError::Interface(iface.to_owned()));
+    }
+    if !validate_member_name(member) {
+        return Err(ValidationError::Member(member.to_owned()));
+    }
+    Ok(())
+}
+
+/// Like create_method_call, but returns an error instead of creating a Message that the bus would
+/// reject if the path, interface or method name isn't valid.
+pub fn create_method_call_checked(dest: &str, path: &str, iface: &str, method: &str) -> Result<Message,ValidationError> {
+    try!(check_names(path, iface, method));
+    Ok(create_method_call(dest, path, iface, method))
+}
+
+/// Like create_signal, but returns an error instead of creating a Message that the bus would
+/// reject if the path, interface or member name isn't valid.
+pub fn create_signal_checked(path: &str, interface: &str, member: &str) -> Result<Message,ValidationError> {
+    try!(check_names(path, interface, member));
+    Ok(create_signal(path, interface, member))
+}
+
 /// Create a Message for a D-Bus method call.  Once a Message is created, arguments
 /// can be added with Message.add_arg
 pub fn create_method_call (dest: &str, path: &str, iface: &str, method: &str) -> Message {
@@ -94,6 +277,7 @@ pub fn create_method_call (dest: &str, path: &str, iface: &str, method: &str) ->
         body: Vec::new(),
 
         body_cache: RefCell::new(None),
+        unix_fds: Default::default(),
     }.add_header(HEADER_FIELD_DESTINATION,
                  Variant::new(Value::from(dest), "s"))
      .add_header(HEADER_FIELD_PATH,
@@ -117,10 +301,22 @@ pub fn create_method_return(reply_serial: u32) -> Message {
         body: Vec::new(),
 
         body_cache: RefCell::new(None),
+        unix_fds: Default::default(),
     }.add_header(HEADER_FIELD_REPLY_SERIAL,
                  Variant::new(Value::from(reply_serial), "u"))
 }
 
+/// Create a Message for a D-Bus method return in reply to the given method call.  The reply
+/// serial is taken from the call, and the reply is addressed to the call's sender, if it has one.
+/// Once created, return values can be added with Message.add_arg
+pub fn create_reply(call: &Message) -> Message {
+    let reply = create_method_return(call.serial);
+    match call.get_header(HEADER_FIELD_SENDER) {
+        Some(sender) => reply.add_header(HEADER_FIELD_DESTINATION, sender.clone()),
+        None => reply
+    }
+}
+
 /// Create a Message for a D-Bus error.  Once created, return values can be added
 /// with Message.add_arg
 pub fn create_error(error_name: &str, reply_serial: u32) -> Message {
@@ -134,6 +330,7 @@ pub fn create_error(error_name: &str, reply_serial: u32) -> Message {
         body: Vec::new(),
 
         body_cache: RefCell::new(None),
+        unix_fds: Default::default(),
     }.add_header(HEADER_FIELD_REPLY_SERIAL,
                  Variant::new(Value::from(reply_serial), "u"))
      .add_header(HEADER_FIELD_ERROR_NAME,
@@ -153,6 +350,7 @@ pub fn create_signal(path: &str, interface: &str, member: &str) -> Message {
         body: Vec::new(),
 
         body_cache: RefCell::new(None),
+        unix_fds: Default::default(),
     }.add_header(HEADER_FIELD_PATH,
                  Variant::new(Value::BasicValue(BasicValue::ObjectPath(Path(path.to_owned()))), "o"))
      .add_header(HEADER_FIELD_INTERFACE,
@@ -161,20 +359,134 @@ pub fn create_signal(path: &str, interface: &str, member: &str) -> Message {
                  Variant::new(Value::from(member), "s"))
 }
 
+/// Extracts the paths from a sequence of object path Values, such as the elements of an "ao"
+/// array.  Returns None if any of the Values isn't an object path.
+pub fn as_object_paths(values: &[Value]) -> Option<Vec<String>> {
+    values.iter().map(|x| match *x {
+        Value::BasicValue(BasicValue::ObjectPath(ref p)) => Some(p.0.clone()),
+        _ => None
+    }).collect()
+}
+
+/// Extracts the strings from a sequence of string Values, such as the elements of an "as" array.
+/// Returns None if any of the Values isn't a string.
+pub fn as_strings(values: &[Value]) -> Option<Vec<String>> {
+    values.iter().map(|x| match *x {
+        Value::BasicValue(BasicValue::String(ref s)) => Some(s.clone()),
+        _ => None
+    }).collect()
+}
+
 impl Message {
+    /// Returns the byte order of the message
+    pub fn endianness(&self) -> Endianness {
+        if self.big_endian { Endianness::Big } else { Endianness::Little }
+    }
+
+    /// Switches the message to the given byte order, converting any arguments that have already
+    /// been added.  Use this rather than setting big_endian directly, which leaves the body in
+    /// the old order.
+    pub fn with_endianness(mut self, order: Endianness) -> Message {
+        if order == self.endianness() {
+            return self;
+        }
+        let sig = match self.get_header(HEADER_FIELD_SIGNATURE).map(|x| &*x.object) {
+            Some(&Value::BasicValue(BasicValue::Signature(ref x))) => x.0.clone(),
+            _ => "".to_owned()
+        };
+        let mut sig = "(".to_owned() + &sig + ")";
+        if !self.body.is_empty() {
+            let mut offset = 0;
+            let from = self.endianness();
+            swap_byte_order(&mut self.body, &mut offset, &mut sig, from);
+        }
+        self.big_endian = order == Endianness::Big;
+        *self.body_cache.borrow_mut() = None;
+        self
+    }
+
     /// Add the given argument to the Message.  Accepts anything that implements the Marshal
     /// trait, which is most basic types, as well as the general-purpose
     /// dbus_serialize::types::Value enum.
     ///
     /// Note that these calls can be chained together to add multiple arguments, see the example
     ///
+    /// # Panics
+    /// Panics if the signature header holds something other than a signature.  Use try_add_arg
+    /// to get an error instead.
+    ///
     /// # Examples
     /// ```
     /// dbus_bytestream::message::create_method_call("foo", "/bar", "baz", "bloop")
     ///     .add_arg(&1)
     ///     .add_arg(&"string");
     /// ```
-    pub fn add_arg(mut self, arg: &Marshal) -> Message {
+    pub fn add_arg(self, arg: &Marshal) -> Message {
+        self.try_add_arg(arg).expect("Garbage in signature field")
+    }
+
+    /// Like add_arg, but returns BadSignature instead of panicking if the signature header holds
+    /// something other than a signature.
+    pub fn try_add_arg(mut self, arg: &Marshal) -> Result<Message,DemarshalError> {
+        self = try!(self.append_signature(&arg.get_type()));
+        let order = self.endianness();
+        arg.dbus_encode_with_order(&mut self.body, order);
+        Ok(self)
+    }
+
+    /// Add each of the given arguments to the Message, in order.  This is equivalent to calling
+    /// add_arg for each one, but is convenient when the arguments are built up dynamically.
+    ///
+    /// # Examples
+    /// ```
+    /// use dbus_bytestream::marshal::Marshal;
+    ///
+    /// let args : Vec<Box<Marshal>> = vec![Box::new(1), Box::new("string")];
+    /// dbus_bytestream::message::create_method_call("foo", "/bar", "baz", "bloop")
+    ///     .add_all(args.iter().map(|x| &**x));
+    /// ```
+    pub fn add_all<'a, I: IntoIterator<Item=&'a Marshal>>(mut self, args: I) -> Message {
+        let mut sig = String::new();
+        let order = self.endianness();
+        for arg in args {
+            sig.push_str(&arg.get_type());
+            arg.dbus_encode_with_order(&mut self.body, order);
+        }
+        self.append_signature(&sig).expect("Garbage in signature field")
+    }
+
+    /// Add the given Value to the Message as a variant with signature sig.  This is the dynamic
+    /// equivalent of wrapping a value in dbus_serialize::types::Variant and calling add_arg.
+    ///
+    /// # Panics
+    /// Panics if sig is not the signature of value, or if the signature header is corrupt.
+    ///
+    /// # Examples
+    /// ```
+    /// extern crate dbus_serialize;
+    /// extern crate dbus_bytestream;
+    /// use dbus_serialize::types::Value;
+    ///
+    /// # fn main() {
+    ///
+    /// dbus_bytestream::message::create_method_call("foo", "/bar", "baz", "bloop")
+    ///     .add_variant("u", Value::from(42 as u32));
+    /// # }
+    /// ```
+    pub fn add_variant(self, sig: &str, value: Value) -> Message {
+        self.try_add_variant(sig, value).expect("Value doesn't match the variant's signature")
+    }
+
+    /// Like add_variant, but returns BadSignature instead of panicking if sig is not the signature
+    /// of value, or if the signature header is corrupt.
+    pub fn try_add_variant(self, sig: &str, value: Value) -> Result<Message,DemarshalError> {
+        if value.get_signature() != sig {
+            return Err(DemarshalError::BadSignature);
+        }
+        self.try_add_arg(&Variant::new(value, sig))
+    }
+
+    fn append_signature(mut self, sig: &str) -> Result<Message,DemarshalError> {
         if let None = self.get_header(HEADER_FIELD_SIGNATURE) {
             let value = Value::BasicValue(BasicValue::Signature(Signature("".to_owned())));
             let variant = Variant::new(value, "g");
@@ -184,12 +496,11 @@ impl Message {
             let b : &mut Box<Value> = &mut self.get_header_mut(HEADER_FIELD_SIGNATURE).unwrap().object;
             let val : &mut Value = b.deref_mut();
             match *val {
-                Value::BasicValue(BasicValue::Signature(ref mut s)) => s.0.push_str(&arg.get_type()),
-                _ => panic!("Garbage in signature field")
+                Value::BasicValue(BasicValue::Signature(ref mut s)) => s.0.push_str(sig),
+                _ => return Err(DemarshalError::BadSignature)
             };
         }
-        arg.dbus_encode(&mut self.body);
-        self
+        Ok(self)
     }
 
     pub fn get_header(&self, name: u8) -> Option<&Variant> {
@@ -209,12 +520,224 @@ impl Message {
         self
     }
 
+    /// Sets the header field with the given code to value, whose signature is sig, replacing any
+    /// existing field with that code.  Any code may be used; fields that a receiver doesn't
+    /// understand are passed along untouched.
+    pub fn set_header_value(&mut self, code: u8, value: Value, sig: &str) {
+        let variant = Variant::new(value, sig);
+        match self.get_header_mut(code) {
+            Some(v) => {
+                *v = variant;
+                return;
+            },
+            None => ()
+        };
+        self.headers.push(HeaderField (code, variant));
+    }
+
+    /// Returns the value of the header field with the given code, without the Variant wrapping
+    /// it.
+    pub fn header_value(&self, code: u8) -> Option<&Value> {
+        self.get_header(code).map(|x| &*x.object)
+    }
+
+    /// Returns the message's serial number, which is 0 until it's been sent
+    pub fn get_serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// Returns the serial of the message this is a reply to, or None if it isn't a reply
+    pub fn reply_serial(&self) -> Option<u32> {
+        match self.header_value(HEADER_FIELD_REPLY_SERIAL) {
+            Some(&Value::BasicValue(BasicValue::Uint32(x))) => Some(x),
+            _ => None
+        }
+    }
+
+    fn header_string(&self, code: u8) -> Option<String> {
+        match self.header_value(code) {
+            Some(&Value::BasicValue(BasicValue::String(ref x))) => Some(x.clone()),
+            Some(&Value::BasicValue(BasicValue::ObjectPath(ref x))) => Some(x.0.clone()),
+            _ => None
+        }
+    }
+
+    /// Returns the header fields used for routing the message, without demarshaling the body.  A
+    /// message that's forwarded unchanged never needs its body decoded.
+    pub fn decode_headers_only(&self) -> RoutingInfo {
+        RoutingInfo {
+            message_type: self.message_type,
+            serial: self.serial,
+            path: self.header_string(HEADER_FIELD_PATH),
+            interface: self.header_string(HEADER_FIELD_INTERFACE),
+            member: self.header_string(HEADER_FIELD_MEMBER),
+            error_name: self.header_string(HEADER_FIELD_ERROR_NAME),
+            reply_serial: self.reply_serial(),
+            destination: self.header_string(HEADER_FIELD_DESTINATION),
+            sender: self.header_string(HEADER_FIELD_SENDER),
+        }
+    }
+
+    /// Attaches a file descriptor to the Message, taking ownership of it.  Its index among the
+    /// Message's descriptors is the number of descriptors added before it.  The descriptor is
+    /// closed when the Message is dropped, which for an outgoing message is once it's been sent.
+    ///
+    /// Descriptors can only be sent over UNIX domain sockets.  The bus only accepts them from
+    /// connections that negotiated descriptor passing during authentication, which Connection
+    /// doesn't do yet, so for now this only works between peers connected directly, as with
+    /// Connection::pair.
+    pub fn add_fd(mut self, fd: RawFd) -> Message {
+        self.unix_fds.0.push(fd);
+        let count = self.unix_fds.0.len() as u32;
+        match self.get_header_mut(HEADER_FIELD_UNIX_FDS) {
+            Some(v) => {
+                *v = Variant::new(Value::from(count), "u");
+                return self;
+            },
+            None => ()
+        };
+        self.add_header(HEADER_FIELD_UNIX_FDS, Variant::new(Value::from(count), "u"))
+    }
+
+    /// Takes ownership of the file descriptor at index, which the caller then becomes responsible
+    /// for closing.  Returns None if there's no descriptor at index, or if it's already been
+    /// taken.
+    pub fn take_fd(&mut self, index: usize) -> Option<RawFd> {
+        match self.unix_fds.0.get_mut(index) {
+            Some(fd) if *fd >= 0 => {
+                let x = *fd;
+                *fd = -1;
+                Some(x)
+            },
+            _ => None
+        }
+    }
+
+    pub(crate) fn fds(&self) -> &[RawFd] {
+        &self.unix_fds.0
+    }
+
+    pub(crate) fn set_fds(&mut self, fds: Vec<RawFd>) {
+        self.unix_fds = UnixFds(fds);
+    }
+
+    /// Returns the number of bytes the message takes up on the wire, including its body, so that
+    /// buffers can be sized before encoding it.
+    pub fn wire_len(&self) -> usize {
+        // The fixed part of the header, and the length of the header field array
+        let mut len = 16;
+        for h in self.headers.iter() {
+            // Each field starts on an 8-byte boundary with its code and the variant's signature
+            len = align_len(len, 8) + 1 + 1 + h.1.signature.0.len() + 1;
+            len = match *h.1.object {
+                Value::BasicValue(BasicValue::String(ref x)) =>
+                    align_len(len, 4) + 4 + x.len() + 1,
+                Value::BasicValue(BasicValue::ObjectPath(ref x)) =>
+                    align_len(len, 4) + 4 + x.0.len() + 1,
+                Value::BasicValue(BasicValue::Signature(ref x)) => len + 1 + x.0.len() + 1,
+                Value::BasicValue(BasicValue::Uint32(_)) => align_len(len, 4) + 4,
+                ref x => {
+                    // Anything else is rare enough to just encode.  Starting at the same offset
+                    // modulo 8 gives the same padding.
+                    let mut buf = vec![0; len % 8];
+                    x.dbus_encode(&mut buf);
+                    len + buf.len() - len % 8
+                }
+            };
+        }
+        align_len(len, 8) + self.body.len()
+    }
+
+    /// Encodes the message, followed by its body, exactly as it's sent on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.wire_len());
+        self.dbus_encode(&mut buf);
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+
+    /// Decodes a message from the bytes that were sent on the wire, as produced by to_bytes.  buf
+    /// must hold exactly one message.  As with messages read from a Connection, the body isn't
+    /// demarshaled until get_body is called.
+    pub fn from_bytes(buf: &[u8]) -> Result<Message,DemarshalError> {
+        let len = try!(framed_message_len(buf));
+        if len != buf.len() {
+            return Err(DemarshalError::CorruptedMessage { offset: len });
+        }
+        // framed_message_len has checked the endianness flag
+        let order = Endianness::from_byte(buf[0]).unwrap();
+
+        let mut msg : Message = Default::default();
+        msg.big_endian = order == Endianness::Big;
+        let mut header = buf.to_vec();
+        let mut offset = 0;
+        let mut sig = "(yyyyuu)".to_owned();
+        let fixed = match try!(demarshal_with_order(&mut header, &mut offset, &mut sig, order)) {
+            Value::Struct(x) => x,
+            x => panic!("Demarshal didn't return what we asked for: {:?}", x)
+        };
+        let mut v = fixed.objects;
+        v.remove(0);
+        msg.message_type = MessageType(DBusDecoder::decode::<u8>(v.remove(0)).unwrap());
+        msg.flags = DBusDecoder::decode::<u8>(v.remove(0)).unwrap();
+        msg.version = DBusDecoder::decode::<u8>(v.remove(0)).unwrap();
+        let body_len = DBusDecoder::decode::<u32>(v.remove(0)).unwrap() as usize;
+        msg.serial = DBusDecoder::decode::<u32>(v.remove(0)).unwrap();
+
+        // Only look at the header, so that the body can't be mistaken for header fields
+        header.truncate(len - body_len);
+        sig = "a(yv)".to_owned();
+        let fields = match try!(demarshal_with_order(&mut header, &mut offset, &mut sig, order)) {
+            Value::Array(x) => x,
+            x => panic!("Demarshal didn't return what we asked for: {:?}", x)
+        };
+        for i in fields.objects {
+            let mut st = match i {
+                Value::Struct(x) => x,
+                x => panic!("Demarshal didn't return what we asked for: {:?}", x)
+            };
+            let variant = match st.objects.remove(1) {
+                Value::Variant(x) => x,
+                x => panic!("Demarshal didn't return what we asked for: {:?}", x)
+            };
+            let code = DBusDecoder::decode::<u8>(st.objects.remove(0)).unwrap();
+            msg.headers.push(HeaderField(code, variant));
+        }
+
+        msg.body = buf[len - body_len..].to_vec();
+        Ok(msg)
+    }
+
+    /// Clears the serial number, so that a copy of a Message that has already been sent can be
+    /// sent again.  The Connection assigns a new serial when the message is sent.
+    pub fn reset_serial(&mut self) {
+        self.serial = 0;
+    }
+
+    /// Returns an iterator over the arguments in the body, which demarshals them one at a time.
+    /// Unlike get_body, nothing is decoded up front, so a caller that only needs the first few
+    /// arguments of a large body can stop early.
+    pub fn iter_args(&self) -> ArgIter {
+        let mut iter = ArgIter {
+            body: Vec::new(),
+            offset: 0,
+            sig: String::new(),
+            order: self.endianness(),
+            error: None,
+        };
+        match self.header_value(HEADER_FIELD_SIGNATURE) {
+            Some(&Value::BasicValue(BasicValue::Signature(ref x))) => iter.sig = x.0.clone(),
+            Some(_) => iter.error = Some(DemarshalError::BadSignature),
+            None => return iter
+        };
+        iter.body = self.body.clone();
+        iter
+    }
+
     /// Get the sequence of Values from out of a Message.  Returns None if the message doesn't have
-    /// a body.
+    /// a body.  Returns BadSignature if the signature header holds something other than a
+    /// signature, and an error if the signature isn't empty but the body is.
     pub fn get_body(&self) -> Result<Option<Vec<Value>>,DemarshalError> {
-        if self.body.is_empty() {
-            return Ok(None);
-        }
         let cached = self.body_cache.borrow().is_some();
         if !cached {
             // Get the signature out of the headers
@@ -225,14 +748,17 @@ impl Message {
 
             let sigval = match *v.object {
                 Value::BasicValue(BasicValue::Signature(ref x)) => x,
-                _ => return Ok(None)
+                _ => return Err(DemarshalError::BadSignature)
             };
+            if sigval.0.is_empty() && self.body.is_empty() {
+                return Ok(None);
+            }
 
             let mut body = self.body.clone();
             let mut sig = "(".to_owned() + &sigval.0 + ")";
             let mut offset = 0;
             *self.body_cache.borrow_mut() = Some((|| {
-                match try!(demarshal(&mut body, &mut offset, &mut sig)) {
+                match try!(demarshal_with_order(&mut body, &mut offset, &mut sig, self.endianness())) {
                     Value::Struct(x) => Ok(Some(x.objects)),
                     x => panic!("Didn't get a struct: {:?}", x)
                 }
@@ -240,6 +766,41 @@ impl Message {
         }
         self.body_cache.borrow().as_ref().unwrap().clone()
     }
+
+    /// Get the sequence of Values from out of a Message, decoding the body according to
+    /// expected_sig rather than the signature the sender put in the headers.  Returns BadSignature
+    /// if the message's signature header disagrees with expected_sig, or if the body doesn't
+    /// contain exactly what expected_sig describes.
+    pub fn get_body_typed(&self, expected_sig: &str) -> Result<Vec<Value>,DemarshalError> {
+        let header_sig = match self.get_header(HEADER_FIELD_SIGNATURE) {
+            Some(v) => match *v.object {
+                Value::BasicValue(BasicValue::Signature(ref x)) => x.0.clone(),
+                _ => return Err(DemarshalError::BadSignature)
+            },
+            None => "".to_owned()
+        };
+        if header_sig != expected_sig {
+            return Err(DemarshalError::BadSignature);
+        }
+        if expected_sig.is_empty() {
+            if !self.body.is_empty() {
+                return Err(DemarshalError::BadSignature);
+            }
+            return Ok(Vec::new());
+        }
+
+        let mut body = self.body.clone();
+        let mut sig = "(".to_owned() + expected_sig + ")";
+        let mut offset = 0;
+        let objects = match try!(demarshal_with_order(&mut body, &mut offset, &mut sig, self.endianness())) {
+            Value::Struct(x) => x.objects,
+            x => panic!("Didn't get a struct: {:?}", x)
+        };
+        if offset != body.len() || !sig.is_empty() {
+            return Err(DemarshalError::BadSignature);
+        }
+        Ok(objects)
+    }
 }
 
 #[test]
@@ -248,3 +809,432 @@ fn test_msg () {
         .add_arg(&1)
         .add_arg(&2);
 }
+
+#[test]
+fn test_create_reply () {
+    let mut call = create_method_call("foo", "/bar", "baz", "floob");
+    call.serial = 42;
+    call = call.add_header(HEADER_FIELD_SENDER, Variant::new(Value::from(":1.7"), "s"));
+
+    let reply = create_reply(&call);
+    assert_eq!(reply.message_type, MESSAGE_TYPE_METHOD_RETURN);
+    assert_eq!(*reply.get_header(HEADER_FIELD_REPLY_SERIAL).unwrap().object, Value::from(42 as u32));
+    assert_eq!(*reply.get_header(HEADER_FIELD_DESTINATION).unwrap().object, Value::from(":1.7"));
+
+    // Without a sender there's nobody to address the reply to
+    let call = create_method_call("foo", "/bar", "baz", "floob");
+    assert!(create_reply(&call).get_header(HEADER_FIELD_DESTINATION).is_none());
+}
+
+#[test]
+fn test_endianness () {
+    assert_eq!(Endianness::from_byte(b'l'), Some(Endianness::Little));
+    assert_eq!(Endianness::from_byte(b'B'), Some(Endianness::Big));
+    assert_eq!(Endianness::from_byte(b'b'), None);
+    assert_eq!(Endianness::Little.to_byte(), b'l');
+    assert_eq!(Endianness::Big.to_byte(), b'B');
+
+    let mut msg = create_signal("/foo", "bar.baz", "floob");
+    assert_eq!(msg.endianness(), Endianness::Little);
+    msg.big_endian = true;
+    assert_eq!(msg.endianness(), Endianness::Big);
+    let mut buf = Vec::new();
+    msg.dbus_encode(&mut buf);
+    assert_eq!(buf[0], b'B');
+
+    let mut msg = create_signal("/foo", "bar.baz", "floob")
+        .with_endianness(Endianness::Big)
+        .add_arg(&(0x01020304 as u32));
+    msg.serial = 42;
+    let mut buf = Vec::new();
+    msg.dbus_encode(&mut buf);
+    assert_eq!(buf[0], b'B');
+    assert_eq!(&buf[4..12], &[0, 0, 0, 4, 0, 0, 0, 42]);
+    assert_eq!(&buf[12..15], &[0, 0, 0]);
+    assert_eq!(msg.body, vec![1, 2, 3, 4]);
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from(0x01020304 as u32)]);
+
+    // Arguments that were already added are converted
+    let little = create_signal("/foo", "bar.baz", "floob")
+        .add_arg(&"hi")
+        .add_arg(&(0x1234 as u16));
+    let big = little.clone().with_endianness(Endianness::Big);
+    assert_eq!(big.body, vec![0, 0, 0, 2, b'h', b'i', 0, 0, 0x12, 0x34]);
+    assert_eq!(big.get_body().unwrap(), little.get_body().unwrap());
+    assert_eq!(big.with_endianness(Endianness::Little).body, little.body);
+}
+
+#[test]
+fn test_wire_len () {
+    let msgs = vec![
+        create_method_call("foo", "/bar", "baz", "floob"),
+        create_method_call("foo", "/bar", "baz", "floob").add_arg(&"hello").add_arg(&42),
+        create_signal("/foo", "bar.baz", "floob").add_arg(&vec![1.5, 2.5]),
+        create_reply(&create_method_call("foo", "/bar", "baz", "floob"))
+            .add_header(200, Variant::new(Value::from(7 as u64), "t")),
+    ];
+    for msg in msgs {
+        let mut buf = Vec::new();
+        msg.dbus_encode(&mut buf);
+        assert_eq!(msg.wire_len(), buf.len() + msg.body.len());
+    }
+}
+
+#[test]
+fn test_get_body_typed () {
+    let msg = create_method_return(1)
+        .add_arg(&(42 as u32))
+        .add_arg(&"foo");
+    assert_eq!(msg.get_body_typed("us").unwrap(), vec![Value::from(42 as u32), Value::from("foo")]);
+    assert!(msg.get_body_typed("u").is_err());
+    assert!(msg.get_body_typed("uss").is_err());
+    assert!(msg.get_body_typed("").is_err());
+
+    // A lying signature header doesn't get past a caller that knows what to expect
+    let mut msg = create_method_return(1).add_arg(&(42 as u32));
+    msg.get_header_mut(HEADER_FIELD_SIGNATURE).unwrap().object =
+        Box::new(Value::BasicValue(BasicValue::Signature(Signature("i".to_owned()))));
+    assert!(msg.get_body_typed("u").is_err());
+
+    let msg = create_method_return(1);
+    assert_eq!(msg.get_body_typed("").unwrap(), vec![]);
+    assert!(msg.get_body_typed("u").is_err());
+}
+
+#[test]
+fn test_get_body_arity () {
+    use dbus_serialize::types::Struct;
+
+    // A single struct argument comes back as one value, not as its members
+    let st = Struct {
+        objects: vec![Value::from(1 as i32), Value::from(2 as i32)],
+        signature: Signature("(ii)".to_owned())
+    };
+    let msg = create_method_return(1).add_arg(&st);
+    let body = msg.get_body().unwrap().unwrap();
+    assert_eq!(body.len(), 1);
+    assert_eq!(body[0], Value::Struct(st));
+
+    let msg = create_method_return(1)
+        .add_arg(&(1 as i32))
+        .add_arg(&(2 as i32));
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from(1 as i32), Value::from(2 as i32)]);
+}
+
+#[test]
+fn test_get_body_empty () {
+    // No signature header at all
+    let msg = create_method_return(1);
+    assert_eq!(msg.get_body().unwrap(), None);
+
+    // An empty signature with an empty body is a message with no arguments
+    let mut msg = create_method_return(1);
+    msg.set_header_value(HEADER_FIELD_SIGNATURE,
+                         Value::BasicValue(BasicValue::Signature(Signature("".to_owned()))), "g");
+    assert_eq!(msg.get_body().unwrap(), None);
+    assert_eq!(msg.get_body_typed("").unwrap(), vec![]);
+
+    // A signature promising arguments that aren't there
+    let mut msg = create_method_return(1).add_arg(&(42 as u32));
+    msg.body.clear();
+    match msg.get_body() {
+        Err(DemarshalError::MessageTooShort { offset }) => assert_eq!(offset, 0),
+        x => panic!("Expected MessageTooShort, got {:?}", x)
+    };
+    assert!(msg.get_body_typed("u").is_err());
+}
+
+#[test]
+fn test_get_body_bad_signature_header () {
+    let mut msg = create_method_return(1).add_arg(&(42 as u32));
+    msg.get_header_mut(HEADER_FIELD_SIGNATURE).unwrap().object = Box::new(Value::from("u"));
+    match msg.get_body() {
+        Err(DemarshalError::BadSignature) => (),
+        x => panic!("Expected BadSignature, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_as_object_paths () {
+    let paths = vec![Value::BasicValue(BasicValue::ObjectPath(Path("/a".to_owned()))),
+                     Value::BasicValue(BasicValue::ObjectPath(Path("/b".to_owned())))];
+    assert_eq!(as_object_paths(&paths), Some(vec!["/a".to_owned(), "/b".to_owned()]));
+    assert_eq!(as_strings(&paths), None);
+
+    let strings = vec![Value::from("a"), Value::from("b")];
+    assert_eq!(as_strings(&strings), Some(vec!["a".to_owned(), "b".to_owned()]));
+    assert_eq!(as_object_paths(&strings), None);
+
+    let mixed = vec![Value::from("a"), Value::from(1 as u32)];
+    assert_eq!(as_strings(&mixed), None);
+    assert_eq!(as_strings(&[]), Some(vec![]));
+}
+
+#[test]
+fn test_header_value () {
+    let mut msg = create_signal("/foo", "bar", "baz");
+    assert_eq!(msg.header_value(42), None);
+    msg.set_header_value(42, Value::from(7 as u32), "u");
+    assert_eq!(msg.header_value(42), Some(&Value::from(7 as u32)));
+    msg.set_header_value(42, Value::from("x"), "s");
+    assert_eq!(msg.header_value(42), Some(&Value::from("x")));
+    assert_eq!(msg.headers.iter().filter(|x| x.0 == 42).count(), 1);
+    assert_eq!(msg.header_value(HEADER_FIELD_MEMBER), Some(&Value::from("baz")));
+}
+
+#[test]
+fn test_add_all () {
+    let args : Vec<Box<Marshal>> = vec![Box::new(42 as u32), Box::new("foo"), Box::new(7 as u64)];
+    let msg = create_method_return(1)
+        .add_arg(&(1 as u8))
+        .add_all(args.iter().map(|x| &**x));
+    let golden = create_method_return(1)
+        .add_arg(&(1 as u8))
+        .add_arg(&(42 as u32))
+        .add_arg(&"foo")
+        .add_arg(&(7 as u64));
+    assert_eq!(msg.body, golden.body);
+    assert_eq!(msg.get_header(HEADER_FIELD_SIGNATURE), golden.get_header(HEADER_FIELD_SIGNATURE));
+    assert_eq!(msg.get_body_typed("yust").unwrap().len(), 4);
+}
+
+#[test]
+fn test_add_variant () {
+    let msg = create_method_return(1).add_variant("s", Value::from("foo"));
+    assert_eq!(msg.get_body_typed("v").unwrap(), vec![Value::Variant(Variant::new(Value::from("foo"), "s"))]);
+}
+
+#[test]
+#[should_panic]
+fn test_add_variant_mismatch () {
+    create_method_return(1).add_variant("u", Value::from("foo"));
+}
+
+#[test]
+fn test_try_add_variant () {
+    match create_method_return(1).try_add_variant("u", Value::from("foo")) {
+        Err(DemarshalError::BadSignature) => (),
+        x => panic!("Expected BadSignature, got {:?}", x)
+    };
+    let msg = create_method_return(1).try_add_variant("s", Value::from("foo")).unwrap();
+    assert_eq!(msg.get_body_typed("v").unwrap(), vec![Value::Variant(Variant::new(Value::from("foo"), "s"))]);
+}
+
+#[test]
+fn test_decode_headers_only () {
+    let mut msg = create_method_call("com.test", "/com/test", "com.test.Iface", "Foo")
+        .add_arg(&"hello");
+    msg.serial = 7;
+    // A body that doesn't match the signature shows the body isn't looked at
+    msg.body = vec![0xff];
+    let info = msg.decode_headers_only();
+    assert_eq!(info, RoutingInfo {
+        message_type: MESSAGE_TYPE_METHOD_CALL,
+        serial: 7,
+        path: Some("/com/test".to_owned()),
+        interface: Some("com.test.Iface".to_owned()),
+        member: Some("Foo".to_owned()),
+        destination: Some("com.test".to_owned()),
+        ..Default::default()
+    });
+    assert!(msg.get_body().is_err());
+
+    let info = create_error("com.test.Error", 7).decode_headers_only();
+    assert_eq!(info.message_type, MESSAGE_TYPE_ERROR);
+    assert_eq!(info.error_name, Some("com.test.Error".to_owned()));
+    assert_eq!(info.reply_serial, Some(7));
+    assert_eq!(info.path, None);
+}
+
+#[test]
+fn test_bytes_round_trip () {
+    let msgs = vec![
+        create_method_call("com.test", "/com/test", "com.test", "Foo")
+            .add_arg(&"hello").add_arg(&(42 as u32)),
+        create_method_return(3).add_arg(&vec![1.5 as f64, -2.0]),
+        create_error("com.test.Error", 4).add_arg(&"it broke"),
+        create_signal("/com/test", "com.test", "Bar")
+            .add_arg(&Path("/com/test/obj".to_owned())).add_arg(&(7 as u8)).add_arg(&true),
+    ];
+    for orig in msgs {
+        for order in vec![Endianness::Little, Endianness::Big] {
+            let mut orig = orig.clone().with_endianness(order);
+            orig.serial = 9;
+            let bytes = orig.to_bytes();
+            assert_eq!(bytes.len(), orig.wire_len());
+            let msg = Message::from_bytes(&bytes).unwrap();
+            assert_eq!(msg.message_type, orig.message_type);
+            assert_eq!(msg.endianness(), order);
+            assert_eq!(msg.serial, 9);
+            assert_eq!(msg.headers.len(), orig.headers.len());
+            for h in orig.headers.iter() {
+                assert_eq!(msg.get_header(h.0), Some(&h.1));
+            }
+            assert_eq!(msg.get_body().unwrap(), orig.get_body().unwrap());
+            assert_eq!(msg.to_bytes(), bytes);
+
+            // Anything but exactly one message is refused
+            match Message::from_bytes(&bytes[..bytes.len() - 1]) {
+                Err(DemarshalError::MessageTooShort { .. }) => (),
+                x => panic!("Expected MessageTooShort, got {:?}", x)
+            };
+            let mut longer = bytes.clone();
+            longer.push(0);
+            match Message::from_bytes(&longer) {
+                Err(DemarshalError::CorruptedMessage { offset }) => assert_eq!(offset, bytes.len()),
+                x => panic!("Expected CorruptedMessage, got {:?}", x)
+            };
+        }
+    }
+}
+
+#[test]
+fn test_big_endian_round_trip () {
+    use std::collections::HashMap;
+    use dbus_serialize::types::Struct;
+
+    let build = |order| {
+        let mut dict = HashMap::new();
+        dict.insert("a".to_owned(), 0x0102030405060708 as i64);
+        let mut msg = create_method_call("com.test", "/com/test", "com.test", "Mixed")
+            .with_endianness(order)
+            .add_arg(&(0x1234 as u16))
+            .add_arg(&"hello")
+            .add_arg(&vec![1.5, -2.25])
+            .add_arg(&dict)
+            .add_variant("(ub)", Value::Struct(Struct {
+                objects: vec![Value::from(0xdeadbeef as u32), Value::from(true)],
+                signature: Signature("(ub)".to_owned())
+            }));
+        msg.serial = 3;
+        msg
+    };
+    let little = Message::from_bytes(&build(Endianness::Little).to_bytes()).unwrap();
+    let big_bytes = build(Endianness::Big).to_bytes();
+    assert_eq!(big_bytes[0], b'B');
+    let big = Message::from_bytes(&big_bytes).unwrap();
+    assert_eq!(big.endianness(), Endianness::Big);
+    assert!(big.body != little.body);
+    assert_eq!(big.get_body().unwrap(), little.get_body().unwrap());
+    assert_eq!(big.decode_headers_only(), little.decode_headers_only());
+}
+
+#[test]
+fn test_reply_serial () {
+    let reply = create_method_return(42);
+    assert_eq!(reply.reply_serial(), Some(42));
+    assert_eq!(reply.get_serial(), 0);
+    assert_eq!(create_error("com.test.Error", 7).reply_serial(), Some(7));
+
+    let mut call = create_method_call("foo", "/bar", "baz", "floob");
+    call.serial = 5;
+    assert_eq!(call.reply_serial(), None);
+    assert_eq!(call.get_serial(), 5);
+}
+
+#[test]
+fn test_try_add_arg () {
+    let msg = create_signal("/com/test", "com.test", "Foo").try_add_arg(&"hello").unwrap();
+    assert_eq!(msg.get_body().unwrap().unwrap(), vec![Value::from("hello")]);
+
+    let mut msg = create_signal("/com/test", "com.test", "Foo");
+    msg.set_header_value(HEADER_FIELD_SIGNATURE, Value::from(1 as u32), "u");
+    match msg.try_add_arg(&"hello") {
+        Err(DemarshalError::BadSignature) => (),
+        x => panic!("Expected BadSignature, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_validate_object_path () {
+    for path in vec!["/", "/org/foo", "/org/foo_1/Bar"] {
+        assert!(validate_object_path(path), "{}", path);
+    }
+    for path in vec!["", "org/foo", "/org/", "/org//foo", "/org/foo-bar", "//"] {
+        assert!(!validate_object_path(path), "{}", path);
+    }
+
+    let msg = create_method_call_checked("com.test", "/org/foo", "com.test", "Foo").unwrap();
+    assert_eq!(msg.header_value(HEADER_FIELD_PATH),
+               Some(&Value::BasicValue(BasicValue::ObjectPath(Path("/org/foo".to_owned())))));
+    assert_eq!(create_method_call_checked("com.test", "org/foo", "com.test", "Foo").unwrap_err(),
+               ValidationError::ObjectPath("org/foo".to_owned()));
+}
+
+#[test]
+fn test_validate_names () {
+    let interfaces = vec![
+        ("org.example.Foo", true),
+        ("a.b", true),
+        ("org._1.foo_bar", true),
+        ("interface", false),
+        ("", false),
+        ("org..Foo", false),
+        ("org.example.", false),
+        (".org.example", false),
+        ("org.1example", false),
+        ("org.exa-mple", false),
+    ];
+    for (name, valid) in interfaces {
+        assert_eq!(validate_interface_name(name), valid, "{}", name);
+    }
+    let long = "a.".repeat(127) + "a";
+    assert!(validate_interface_name(&long));
+    assert!(!validate_interface_name(&(long + "b")));
+
+    let members = vec![
+        ("Foo", true),
+        ("_foo1", true),
+        ("", false),
+        ("1foo", false),
+        ("foo.bar", false),
+        ("foo bar", false),
+    ];
+    for (name, valid) in members {
+        assert_eq!(validate_member_name(name), valid, "{}", name);
+    }
+
+    create_signal_checked("/com/test", "com.test", "Foo").unwrap();
+    assert_eq!(create_signal_checked("/com/test", "interface", "Foo").unwrap_err(),
+               ValidationError::Interface("interface".to_owned()));
+    assert_eq!(create_method_call_checked("com.test", "/com/test", "com.test", "Fo.o").unwrap_err(),
+               ValidationError::Member("Fo.o".to_owned()));
+}
+
+#[test]
+fn test_iter_args () {
+    let msg = create_signal("/com/test", "com.test", "Foo")
+        .add_arg(&"first").add_arg(&(42 as u32)).add_arg(&"x".repeat(1000));
+    let mut args = msg.iter_args();
+    assert_eq!(args.next().unwrap().unwrap(), Value::from("first"));
+    // Stopping here means the body was never decoded as a whole
+    assert!(msg.body_cache.borrow().is_none());
+    drop(args);
+
+    let args : Vec<Value> = msg.iter_args().map(Result::unwrap).collect();
+    assert_eq!(args, msg.get_body().unwrap().unwrap());
+
+    let big = msg.clone().with_endianness(Endianness::Big);
+    let args : Vec<Value> = big.iter_args().map(Result::unwrap).collect();
+    assert_eq!(args, msg.get_body().unwrap().unwrap());
+
+    assert_eq!(create_signal("/com/test", "com.test", "Foo").iter_args().count(), 0);
+
+    // A body that's too short is an error, after which the iterator stops
+    let mut short = msg.clone();
+    short.body.truncate(14);
+    let args : Vec<_> = short.iter_args().collect();
+    assert_eq!(args.len(), 2);
+    assert!(args[0].is_ok());
+    assert!(args[1].is_err());
+
+    // So is data that doesn't belong to any argument
+    let mut long = msg.clone();
+    long.body.push(0);
+    let args : Vec<_> = long.iter_args().collect();
+    assert_eq!(args.len(), 4);
+    match args[3] {
+        Err(DemarshalError::CorruptedMessage { offset }) => assert_eq!(offset, msg.body.len() + 1),
+        ref x => panic!("Expected CorruptedMessage, got {:?}", x)
+    };
+}
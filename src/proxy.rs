@@ -0,0 +1,139 @@
+//! A convenience wrapper for making calls to a single remote object.
+//!
+//! # Examples
+//! ```no_run
+//! use dbus_bytestream::connection::Connection;
+//! use dbus_bytestream::proxy::Proxy;
+//!
+//! let conn = Connection::connect_session().unwrap();
+//! let bus = Proxy::new(&conn, "org.freedesktop.DBus", "/org/freedesktop/DBus",
+//!                      "org.freedesktop.DBus");
+//! let names = bus.call("ListNames", &[]).unwrap();
+//! println!("{:?}", names);
+//! ```
+use std::collections::HashMap;
+
+use dbus_serialize::types::Value;
+
+use connection::{Connection,Error};
+use marshal::Marshal;
+use message;
+use message::Message;
+
+const PROPERTIES_INTERFACE : &str = "org.freedesktop.DBus.Properties";
+
+/// A remote object, identified by the bus name that owns it and its object path.  Method calls
+/// and property accesses go to the Proxy's interface.
+pub struct Proxy<'a> {
+    conn: &'a Connection,
+    destination: String,
+    path: String,
+    interface: String,
+}
+
+impl<'a> Proxy<'a> {
+    /// Creates a Proxy for the object at path, owned by destination, which sends calls over conn.
+    pub fn new(conn: &'a Connection, destination: &str, path: &str, interface: &str) -> Proxy<'a> {
+        Proxy {
+            conn,
+            destination: destination.to_owned(),
+            path: path.to_owned(),
+            interface: interface.to_owned(),
+        }
+    }
+
+    fn method_call(&self, interface: &str, method: &str) -> Message {
+        message::create_method_call(&self.destination, &self.path, interface, method)
+    }
+
+    fn call_interface(&self, interface: &str, method: &str, args: &[&Marshal]) -> Result<Vec<Value>,Error> {
+        let msg = self.method_call(interface, method).add_all(args.iter().cloned());
+        self.conn.call_sync(msg).map(Option::unwrap_or_default)
+    }
+
+    /// Calls method on the Proxy's interface with the given arguments, and blocks until the
+    /// reply arrives.  Returns the body of the reply.
+    pub fn call(&self, method: &str, args: &[&Marshal]) -> Result<Vec<Value>,Error> {
+        self.call_interface(&self.interface, method, args)
+    }
+
+    /// Returns the current value of the named property, with the variant that carried it
+    /// unwrapped.
+    pub fn get_property(&self, name: &str) -> Result<Value,Error> {
+        self.conn.get_property_value(&self.destination, &self.path, &self.interface, name)
+    }
+
+    /// Sets the named property to value.
+    pub fn set_property(&self, name: &str, value: &Marshal) -> Result<(),Error> {
+        self.conn.set_property_value(&self.destination, &self.path, &self.interface, name, value)
+    }
+
+    /// Returns all of the properties of the Proxy's interface, keyed by name, with the variants
+    /// that carried them unwrapped.
+    pub fn get_all(&self) -> Result<HashMap<String,Value>,Error> {
+        let msg = self.method_call(PROPERTIES_INTERFACE, "GetAll").add_arg(&&self.interface[..]);
+        self.conn.call_sync_dict(msg)
+    }
+}
+
+#[cfg(test)]
+fn bus_proxy<'a>(conn: &'a Connection) -> Proxy<'a> {
+    Proxy::new(conn, "org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus")
+}
+
+#[test]
+fn test_call() {
+    let conn = Connection::connect_session().unwrap();
+    let bus = bus_proxy(&conn);
+    let resp = bus.call("NameHasOwner", &[&"org.freedesktop.DBus"]).unwrap();
+    assert_eq!(resp, vec![Value::from(true)]);
+}
+
+#[test]
+fn test_get_property() {
+    let conn = Connection::connect_session().unwrap();
+    let bus = bus_proxy(&conn);
+    match bus.get_property("Interfaces").unwrap() {
+        Value::Array(_) => (),
+        x => panic!("Expected an array, got {:?}", x)
+    };
+
+    let props = bus.get_all().unwrap();
+    assert!(props.contains_key("Features"));
+    assert!(props.contains_key("Interfaces"));
+}
+
+#[test]
+fn test_set_property() {
+    use std::thread;
+    use dbus_serialize::decoder::DBusDecoder;
+
+    let server = Connection::connect_session().unwrap();
+    server.request_name("com.test.proxyserver", Default::default()).unwrap();
+
+    let handle = thread::spawn(move || {
+        loop {
+            let call = server.read_msg().unwrap();
+            if call.message_type != message::MESSAGE_TYPE_METHOD_CALL {
+                continue;
+            }
+            server.send(message::create_reply(&call)).unwrap();
+            return call.get_body().unwrap().unwrap();
+        }
+    });
+
+    let client = Connection::connect_session().unwrap();
+    let proxy = Proxy::new(&client, "com.test.proxyserver", "/com/test", "com.test");
+    proxy.set_property("Count", &(42 as u32)).unwrap();
+
+    let args = handle.join().unwrap();
+    assert_eq!(args.len(), 3);
+    assert_eq!(args[0], Value::from("com.test"));
+    assert_eq!(args[1], Value::from("Count"));
+    let variant = match args[2] {
+        Value::Variant(ref x) => x.clone(),
+        ref x => panic!("Expected a variant, got {:?}", x)
+    };
+    assert_eq!(variant.signature.0, "u");
+    assert_eq!(DBusDecoder::decode::<u32>(*variant.object).unwrap(), 42);
+}
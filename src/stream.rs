@@ -0,0 +1,475 @@
+//! Adapters for using a Connection from async code, enabled by the "async" feature.  A
+//! MessageStream yields incoming messages, and a Call resolves to the reply to a method call.
+//! Neither blocks: they read with try_read_msg, and when nothing is available they have their
+//! task woken once the socket becomes readable, so they work with any executor.
+//!
+//! The waking is done by one helper thread per Connection, which is started the first time a
+//! task has to wait, sleeps while no task is waiting, and is stopped when the Connection is
+//! dropped.  Runtimes with a reactor of their own can instead wait on the Connection's as_raw_fd
+//! and call try_read_msg directly.
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd,RawFd};
+use std::pin::Pin;
+use std::sync::{Arc,Mutex};
+use std::task::{Context,Poll,Waker};
+use std::thread;
+use std::thread::JoinHandle;
+use libc;
+
+use dbus_serialize::types::Value;
+
+use connection::{Connection,Error,method_error};
+use message;
+use message::Message;
+
+struct Waiting {
+    wakers: Vec<Waker>,
+    quit: bool,
+}
+
+/// Wakes tasks once a socket is readable.  The helper thread that does the waiting watches a
+/// pipe along with the socket, so that it can be told about new tasks and be stopped.
+struct ReadWatcher {
+    shared: Arc<Mutex<Waiting>>,
+    // The read and write ends of the helper thread's pipe
+    pipe: [RawFd; 2],
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReadWatcher {
+    fn new() -> ReadWatcher {
+        ReadWatcher {
+            shared: Arc::new(Mutex::new(Waiting { wakers: Vec::new(), quit: false })),
+            pipe: [-1, -1],
+            thread: None,
+        }
+    }
+
+    /// Arranges for waker to be woken the next time fd is readable.
+    fn watch(&mut self, fd: RawFd, waker: &Waker) -> io::Result<()> {
+        // Start over if the helper thread gave up after poll failed
+        if self.thread.as_ref().is_some_and(|x| x.is_finished()) {
+            self.stop();
+            self.shared.lock().unwrap().quit = false;
+        }
+        if self.thread.is_none() {
+            try!(self.start(fd));
+        }
+        {
+            let mut waiting = self.shared.lock().unwrap();
+            if waiting.wakers.iter().any(|x| x.will_wake(waker)) {
+                return Ok(());
+            }
+            waiting.wakers.push(waker.clone());
+        }
+        self.notify();
+        Ok(())
+    }
+
+    fn start(&mut self, fd: RawFd) -> io::Result<()> {
+        if unsafe { libc::pipe2(self.pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let shared = self.shared.clone();
+        let wake_fd = self.pipe[0];
+        let res = thread::Builder::new().spawn(move || watch_thread(fd, wake_fd, shared));
+        match res {
+            Ok(x) => self.thread = Some(x),
+            Err(e) => {
+                self.close_pipe();
+                return Err(e);
+            }
+        };
+        Ok(())
+    }
+
+    /// Makes the helper thread look at the shared state again
+    fn notify(&self) {
+        let byte = 0u8;
+        unsafe { libc::write(self.pipe[1], &byte as *const u8 as *const libc::c_void, 1); }
+    }
+
+    fn close_pipe(&mut self) {
+        for fd in self.pipe.iter_mut() {
+            unsafe { libc::close(*fd); }
+            *fd = -1;
+        }
+    }
+
+    /// Stops the helper thread, and waits for it to finish so that it's done with the socket.
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shared.lock().unwrap().quit = true;
+            self.notify();
+            let _ = thread.join();
+            self.close_pipe();
+        }
+    }
+}
+
+impl Drop for ReadWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn watch_thread(fd: RawFd, wake_fd: RawFd, shared: Arc<Mutex<Waiting>>) {
+    loop {
+        let armed = {
+            let waiting = shared.lock().unwrap();
+            if waiting.quit {
+                return;
+            }
+            !waiting.wakers.is_empty()
+        };
+        // poll ignores negative descriptors, so the socket is only watched while a task waits
+        let mut fds = [
+            libc::pollfd { fd: if armed { fd } else { -1 }, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: wake_fd, events: libc::POLLIN, revents: 0 },
+        ];
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) };
+        if ret < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        if fds[1].revents != 0 {
+            let mut buf = [0u8; 64];
+            while unsafe { libc::read(wake_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 { }
+        }
+        if ret < 0 || fds[0].revents != 0 {
+            let wakers = mem::take(&mut shared.lock().unwrap().wakers);
+            for w in wakers {
+                w.wake();
+            }
+        }
+        if ret < 0 {
+            // The tasks that were just woken will find out what's wrong when they read
+            return;
+        }
+    }
+}
+
+/// The async state of a Connection
+pub(crate) struct AsyncState {
+    watcher: ReadWatcher,
+    // The serials of calls made with call_async that haven't resolved yet, each with the waker of
+    // the task that's waiting for it
+    calls: HashMap<u32, Option<Waker>>,
+    // Replies that were read by something other than the Call they belong to
+    replies: HashMap<u32, Message>,
+    // The task waiting on a MessageStream
+    stream: Option<Waker>,
+}
+
+impl AsyncState {
+    pub(crate) fn new() -> AsyncState {
+        AsyncState {
+            watcher: ReadWatcher::new(),
+            calls: HashMap::new(),
+            replies: HashMap::new(),
+            stream: None,
+        }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        self.watcher.stop();
+    }
+}
+
+/// The messages coming in on a Connection, as returned by Connection::messages.  Ends when the
+/// peer closes the connection.  Replies to calls made with call_async go to the Call rather than
+/// the stream.  If several streams are waiting on one Connection, only the last to wait is woken
+/// for messages that a Call reads.
+pub struct MessageStream<'a> {
+    conn: &'a Connection,
+}
+
+impl<'a> MessageStream<'a> {
+    /// Returns the next message if one is available, and otherwise arranges for the task to be
+    /// woken when one might be.  This is the same as futures::Stream::poll_next.
+    pub fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Result<Message,Error>>> {
+        loop {
+            match self.conn.try_read_msg() {
+                Ok(Some(msg)) => {
+                    if let Some(msg) = self.conn.route_reply(msg) {
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                },
+                Ok(None) => {
+                    self.conn.async_state.borrow_mut().stream = Some(cx.waker().clone());
+                    if let Err(e) = self.conn.wait_readable(cx.waker()) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    return Poll::Pending;
+                },
+                Err(Error::Disconnected) => return Poll::Ready(None),
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+
+    /// Returns a future that resolves to the next message, or None if the connection was closed.
+    pub fn next<'b>(&'b mut self) -> Next<'b, 'a> {
+        Next { stream: self }
+    }
+}
+
+/// The future returned by MessageStream::next
+pub struct Next<'b, 'a: 'b> {
+    stream: &'b mut MessageStream<'a>,
+}
+
+impl<'b, 'a> Future for Next<'b, 'a> {
+    type Output = Option<Result<Message,Error>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_next(cx)
+    }
+}
+
+/// A method call that has been sent, as returned by Connection::call_async.  Resolves to the body
+/// of the reply, like call_sync.  If it's dropped first, the reply is queued for read_msg like
+/// any other message.
+pub struct Call<'a> {
+    conn: &'a Connection,
+    serial: u32,
+    error: Option<Error>,
+}
+
+impl<'a> Call<'a> {
+    fn finish(&self, msg: Message) -> Result<Option<Vec<Value>>,Error> {
+        self.conn.async_state.borrow_mut().calls.remove(&self.serial);
+        if msg.message_type == message::MESSAGE_TYPE_ERROR {
+            return Err(method_error(&msg));
+        }
+        Ok(try!(msg.get_body()))
+    }
+}
+
+impl<'a> Future for Call<'a> {
+    type Output = Result<Option<Vec<Value>>,Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let call = self.get_mut();
+        if let Some(e) = call.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        // Someone else may have read the reply already
+        let reply = call.conn.async_state.borrow_mut().replies.remove(&call.serial);
+        if let Some(msg) = reply.or_else(|| call.conn.take_reply(call.serial)) {
+            return Poll::Ready(call.finish(msg));
+        }
+        loop {
+            match call.conn.try_read_msg_raw() {
+                Ok(Some(msg)) => {
+                    if msg.reply_serial() == Some(call.serial) {
+                        return Poll::Ready(call.finish(msg));
+                    }
+                    if let Some(msg) = call.conn.route_reply(msg) {
+                        call.conn.queue_msg(msg);
+                        let waker = call.conn.async_state.borrow_mut().stream.take();
+                        if let Some(w) = waker {
+                            w.wake();
+                        }
+                    }
+                },
+                Ok(None) => {
+                    call.conn.async_state.borrow_mut().calls.insert(call.serial, Some(cx.waker().clone()));
+                    if let Err(e) = call.conn.wait_readable(cx.waker()) {
+                        return Poll::Ready(Err(e));
+                    }
+                    return Poll::Pending;
+                },
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Call<'a> {
+    fn drop(&mut self) {
+        let reply = {
+            let mut state = self.conn.async_state.borrow_mut();
+            state.calls.remove(&self.serial);
+            state.replies.remove(&self.serial)
+        };
+        if let Some(msg) = reply {
+            self.conn.queue_msg(msg);
+        }
+    }
+}
+
+impl Connection {
+    /// Returns a stream of the messages coming in on this connection.
+    pub fn messages<'a>(&'a self) -> MessageStream<'a> {
+        MessageStream { conn: self }
+    }
+
+    /// Sends a method call, and returns a future for its reply.  Other messages that arrive
+    /// first are queued, as with call_sync.  Returns Error::InvalidMessageForCall, as call_sync
+    /// does, for a message that isn't a method call expecting a reply.
+    pub fn call_async<'a>(&'a self, mbuf: Message) -> Call<'a> {
+        let mut call = Call { conn: self, serial: 0, error: None };
+        if mbuf.message_type != message::MESSAGE_TYPE_METHOD_CALL ||
+           mbuf.flags & message::FLAGS_NO_REPLY_EXPECTED != 0 {
+            call.error = Some(Error::InvalidMessageForCall);
+            return call;
+        }
+        match self.send(mbuf) {
+            Ok(serial) => {
+                call.serial = serial;
+                self.async_state.borrow_mut().calls.insert(serial, None);
+            },
+            Err(e) => call.error = Some(e)
+        };
+        call
+    }
+
+    /// Hands msg to the Call that it's the reply to, and wakes that Call's task.  Returns msg if
+    /// it isn't the reply to any outstanding Call.
+    fn route_reply(&self, msg: Message) -> Option<Message> {
+        let waker = {
+            let mut state = self.async_state.borrow_mut();
+            let serial = match msg.reply_serial() {
+                Some(x) if state.calls.contains_key(&x) => x,
+                _ => return Some(msg)
+            };
+            state.replies.insert(serial, msg);
+            state.calls.get_mut(&serial).and_then(|x| x.take())
+        };
+        if let Some(w) = waker {
+            w.wake();
+        }
+        None
+    }
+
+    fn wait_readable(&self, waker: &Waker) -> Result<(),Error> {
+        let fd = self.as_raw_fd();
+        try!(self.async_state.borrow_mut().watcher.watch(fd, waker));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct FlagWaker(::std::sync::atomic::AtomicBool, thread::Thread);
+
+#[cfg(test)]
+impl ::std::task::Wake for FlagWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, ::std::sync::atomic::Ordering::SeqCst);
+        self.1.unpark();
+    }
+}
+
+#[cfg(test)]
+fn flag_waker() -> (Arc<FlagWaker>, Waker) {
+    let flag = Arc::new(FlagWaker(::std::sync::atomic::AtomicBool::new(false), thread::current()));
+    (flag.clone(), Waker::from(flag))
+}
+
+#[cfg(test)]
+fn block_on<F: Future>(f: F) -> F::Output {
+    let (_, waker) = flag_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut f = Box::pin(f);
+    loop {
+        if let Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+            return x;
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn test_messages() {
+    use std::time::Duration;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        // Give the stream a chance to wait before anything arrives
+        thread::sleep(Duration::from_millis(50));
+        server.send(message::create_signal("/com/test", "com.test", "First")).unwrap();
+        server.send(message::create_signal("/com/test", "com.test", "Second")).unwrap();
+    });
+
+    let mut stream = client.messages();
+    let msg = block_on(stream.next()).unwrap().unwrap();
+    assert_eq!(msg.decode_headers_only().member, Some("First".to_owned()));
+    let msg = block_on(stream.next()).unwrap().unwrap();
+    assert_eq!(msg.decode_headers_only().member, Some("Second".to_owned()));
+
+    handle.join().unwrap();
+    assert!(block_on(stream.next()).is_none());
+}
+
+#[test]
+fn test_call_async() {
+    use std::time::Duration;
+
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        server.send(message::create_signal("/com/test", "com.test", "Busy")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        server.send(message::create_reply(&call).add_arg(&"done")).unwrap();
+        let call = server.read_msg().unwrap();
+        server.send(message::create_error("com.test.Failed", call.get_serial())).unwrap();
+    });
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Slow");
+    assert_eq!(block_on(client.call_async(msg)).unwrap().unwrap(), vec![Value::from("done")]);
+    // The signal that came first is still there
+    let sig = client.read_msg().unwrap();
+    assert_eq!(sig.message_type, message::MESSAGE_TYPE_SIGNAL);
+
+    let msg = message::create_method_call("com.test", "/com/test", "com.test", "Fail");
+    match block_on(client.call_async(msg)) {
+        Err(Error::MethodError { ref name, .. }) => assert_eq!(name, "com.test.Failed"),
+        x => panic!("Expected MethodError, got {:?}", x)
+    };
+    handle.join().unwrap();
+
+    let sig = message::create_signal("/com/test", "com.test", "Foo");
+    match block_on(client.call_async(sig)) {
+        Err(Error::InvalidMessageForCall) => (),
+        x => panic!("Expected InvalidMessageForCall, got {:?}", x)
+    };
+}
+
+#[test]
+fn test_stream_and_call() {
+    let (client, server) = Connection::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let call = server.read_msg().unwrap();
+        server.send(message::create_reply(&call).add_arg(&call.get_serial())).unwrap();
+        server.send(message::create_signal("/com/test", "com.test", "After")).unwrap();
+        let call = server.read_msg().unwrap();
+        server.send(message::create_signal("/com/test", "com.test", "Before")).unwrap();
+        server.send(message::create_reply(&call).add_arg(&call.get_serial())).unwrap();
+    });
+
+    // A reply that the stream reads goes to its Call instead of being returned
+    let mut stream = client.messages();
+    let call = client.call_async(message::create_method_call("com.test", "/com/test", "com.test", "A"));
+    let msg = block_on(stream.next()).unwrap().unwrap();
+    assert_eq!(msg.decode_headers_only().member, Some("After".to_owned()));
+    assert_eq!(block_on(call).unwrap().unwrap().len(), 1);
+
+    // A message that a Call reads wakes the task waiting on the stream
+    let (flag, waker) = flag_waker();
+    let mut next = Box::pin(stream.next());
+    assert!(next.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    let call = client.call_async(message::create_method_call("com.test", "/com/test", "com.test", "B"));
+    assert_eq!(block_on(call).unwrap().unwrap().len(), 1);
+    let msg = loop {
+        if let Poll::Ready(x) = next.as_mut().poll(&mut Context::from_waker(&waker)) {
+            break x.unwrap().unwrap();
+        }
+        thread::park();
+    };
+    assert!(flag.0.load(::std::sync::atomic::Ordering::SeqCst));
+    assert_eq!(msg.decode_headers_only().member, Some("Before".to_owned()));
+    handle.join().unwrap();
+}